@@ -0,0 +1,225 @@
+//! Shields-style SVG badges showing a count.
+//!
+//! Text widths are estimated rather than measured, so very wide or narrow
+//! glyphs make the badge a bit off, but it never needs a font.
+use std::fmt::Write;
+
+/// Max number of characters of a badge label.
+pub const MAX_LABEL_LEN: usize = 40;
+
+/// Estimated width of one character, in pixels, at the badge font size.
+const CHAR_WIDTH: usize = 7;
+
+/// Space on each side of the label and the count.
+const PADDING: usize = 6;
+
+/// Shape of the badge corners.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Corners {
+    /// Slightly rounded, like shields.io's "flat".
+    #[default]
+    Rounded,
+
+    /// Square, like shields.io's "flat-square".
+    Square,
+}
+
+impl std::str::FromStr for Corners {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(Corners::Rounded),
+            "flat-square" => Ok(Corners::Square),
+            _ => Err(format!(
+                "invalid badge style {s:?}, must be \"flat\" or \"flat-square\""
+            )),
+        }
+    }
+}
+
+/// How the count of a badge is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// All digits, like "1234".
+    #[default]
+    Exact,
+
+    /// Rounded down to at most three digits and a unit, like "1.2k".
+    Compact,
+}
+
+impl Rounding {
+    fn format(self, count: u64) -> String {
+        const UNITS: [&str; 6] = ["k", "M", "G", "T", "P", "E"];
+        if self == Rounding::Exact || count < 1000 {
+            return count.to_string();
+        }
+        let mut unit = 0;
+        let mut scale = 1000;
+        while count / scale >= 1000 && unit + 1 < UNITS.len() {
+            scale *= 1000;
+            unit += 1;
+        }
+        let whole = count / scale;
+        let tenths = count % scale / (scale / 10);
+        // Rounding down, so that 999_999 isn't shown as "1000k".
+        if whole < 10 && tenths > 0 {
+            format!("{whole}.{tenths}{}", UNITS[unit])
+        } else {
+            format!("{whole}{}", UNITS[unit])
+        }
+    }
+}
+
+impl std::str::FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(Rounding::Exact),
+            "compact" => Ok(Rounding::Compact),
+            _ => Err(format!(
+                "invalid badge rounding {s:?}, must be \"exact\" or \"compact\""
+            )),
+        }
+    }
+}
+
+/// How a badge looks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Style {
+    /// Text left of the count.
+    pub label: String,
+
+    /// Background of the label, as a color accepted by [`parse_color`].
+    pub label_color: String,
+
+    /// Background of the count.
+    pub color: String,
+
+    pub corners: Corners,
+
+    pub rounding: Rounding,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            label: "viewers".to_string(),
+            label_color: "#555".to_string(),
+            color: "#4c1".to_string(),
+            corners: Corners::Rounded,
+            rounding: Rounding::Exact,
+        }
+    }
+}
+
+/// Parse a badge color, given as hex RGB with or without the `#`, or as a
+/// plain color name like "green".
+///
+/// Only these are accepted, since colors are put into the SVG as is.
+pub fn parse_color(color: &str) -> Option<String> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if matches!(hex.len(), 3 | 6) && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Some(format!("#{hex}"));
+    }
+    if !color.is_empty() && color.len() <= 20 && color.bytes().all(|b| b.is_ascii_lowercase()) {
+        return Some(color.to_string());
+    }
+    None
+}
+
+/// Escape text for use in SVG text and attributes.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Render a badge for `count`.
+pub fn render(count: u64, style: &Style) -> String {
+    let label = escape(&style.label);
+    let value = style.rounding.format(count);
+    let label_width = style.label.chars().count() * CHAR_WIDTH + 2 * PADDING;
+    let value_width = value.len() * CHAR_WIDTH + 2 * PADDING;
+    let width = label_width + value_width;
+    let rx = match style.corners {
+        Corners::Rounded => 3,
+        Corners::Square => 0,
+    };
+
+    let mut svg = String::new();
+    // Writing to a String can't fail.
+    let _ = write!(
+        svg,
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {value}"><title>{label}: {value}</title><clipPath id="r"><rect width="{width}" height="20" rx="{rx}" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="{label_color}"/><rect x="{label_width}" width="{value_width}" height="20" fill="{color}"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{label_x}" y="14">{label}</text><text x="{value_x}" y="14">{value}</text></g></svg>"##,
+        label_color = style.label_color,
+        color = style.color,
+        label_x = label_width / 2,
+        value_x = label_width + value_width / 2,
+    );
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_color, render, Corners, Rounding, Style};
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(parse_color("4c1").as_deref(), Some("#4c1"));
+        assert_eq!(parse_color("#00FF00").as_deref(), Some("#00FF00"));
+        assert_eq!(parse_color("green").as_deref(), Some("green"));
+        assert_eq!(parse_color("12345"), None);
+        assert_eq!(parse_color("red\"/><script>"), None);
+        assert_eq!(parse_color(""), None);
+    }
+
+    #[test]
+    fn renders_badges() {
+        let svg = render(42, &Style::default());
+        assert!(svg.starts_with("<svg "), "{svg}");
+        assert!(svg.contains(r#"width="87""#), "{svg}");
+        assert!(svg.contains(">viewers</text>"), "{svg}");
+        assert!(svg.contains(">42</text>"), "{svg}");
+        assert!(svg.contains(r#"rx="3""#), "{svg}");
+
+        let style = Style {
+            label: "<b>&".to_string(),
+            corners: Corners::Square,
+            ..Style::default()
+        };
+        let svg = render(1, &style);
+        assert!(svg.contains(">&lt;b&gt;&amp;</text>"), "{svg}");
+        assert!(svg.contains(r#"rx="0""#), "{svg}");
+    }
+
+    #[test]
+    fn formats_compact_counts() {
+        for (count, text) in [
+            (0, "0"),
+            (999, "999"),
+            (1000, "1k"),
+            (1234, "1.2k"),
+            (1999, "1.9k"),
+            (12_345, "12k"),
+            (999_999, "999k"),
+            (1_500_000, "1.5M"),
+            (2_000_000_000, "2G"),
+            (u64::MAX, "18E"),
+        ] {
+            assert_eq!(Rounding::Compact.format(count), text, "{count}");
+        }
+        assert_eq!(Rounding::Exact.format(1234), "1234");
+    }
+}
@@ -0,0 +1,315 @@
+//! Page keys, from page URLs.
+//!
+//! The same page is often reachable under many URLs, e.g. with and without
+//! a trailing slash or `index.html`, or from a `www.` host. [`Rules`] turn
+//! them into one key, so that their viewers are counted together.
+//!
+//! [`Allowlist`] then decides which keys may be counted at all, so that
+//! clients can't make up pages, each using memory and a metric label.
+use std::collections::HashMap;
+
+use url::Url;
+
+/// Default max length of a page key.
+pub const DEFAULT_MAX_KEY_LEN: usize = 1024;
+
+/// How to canonicalize page URLs into keys.
+///
+/// Hosts are always lowercased, and fragments always removed. The default
+/// rules also remove the whole query, and do nothing else.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    /// Count `http` pages as their `https` counterparts.
+    pub merge_schemes: bool,
+
+    /// Remove trailing slashes from paths, other than the root path.
+    pub strip_trailing_slash: bool,
+
+    /// Last path segments that are removed, e.g. "index.html", leaving the
+    /// path of the directory.
+    pub index_files: Vec<String>,
+
+    /// Query parameters that are kept, e.g. "page" for paginated articles.
+    /// Others are removed.
+    pub query_params: Vec<String>,
+
+    /// Hosts counted as other hosts, e.g. "www.example.com" as
+    /// "example.com".
+    pub host_aliases: HashMap<String, String>,
+}
+
+impl Rules {
+    /// Get the key of a page.
+    pub fn key(&self, url: &Url) -> String {
+        self.canonicalize(url).into()
+    }
+
+    /// Get the canonical URL of a page.
+    pub fn canonicalize(&self, url: &Url) -> Url {
+        let mut url = url.clone();
+        url.set_fragment(None);
+
+        if let Some(alias) = url
+            .host_str()
+            .and_then(|h| self.host_aliases.get(h))
+            .cloned()
+        {
+            // Aliases are parsed as hosts, so this can't fail.
+            let _ = url.set_host(Some(&alias));
+        }
+        if self.merge_schemes && url.scheme() == "http" {
+            let _ = url.set_scheme("https");
+        }
+
+        let mut path = url.path().to_owned();
+        if let Some((dir, file)) = path.rsplit_once('/') {
+            if self.index_files.iter().any(|f| f == file) {
+                path = format!("{dir}/");
+            }
+        }
+        if self.strip_trailing_slash {
+            let trimmed = path.trim_end_matches('/');
+            path = if trimmed.is_empty() {
+                "/".to_owned()
+            } else {
+                trimmed.to_owned()
+            };
+        }
+        if path != url.path() {
+            url.set_path(&path);
+        }
+
+        // Kept parameters are sorted, so that their order doesn't matter.
+        let mut params: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| self.query_params.iter().any(|p| p == name))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        params.sort();
+        if params.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(params);
+        }
+        url
+    }
+}
+
+/// Why a page may not be counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// Not an http or https URL with a host, or has a user name or password.
+    Invalid,
+
+    /// The key is this long, longer than the max.
+    TooLong(usize),
+
+    /// Not under any allowed prefix.
+    NotAllowed,
+}
+
+impl Rejection {
+    /// Reason for metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::Invalid => "invalid",
+            Rejection::TooLong(_) => "too_long",
+            Rejection::NotAllowed => "not_allowed",
+        }
+    }
+}
+
+/// A host, and a path prefix of pages on it, written like
+/// "example.com/blog/". Without a path, all pages on the host match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    host: String,
+    path: String,
+}
+
+impl std::str::FromStr for Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, path) = match s.find('/') {
+            Some(n) => s.split_at(n),
+            None => (s, "/"),
+        };
+        let host = url::Host::parse(host)
+            .map_err(|e| format!("invalid host in page prefix {s:?}: {e}"))?;
+        Ok(Prefix {
+            host: host.to_string(),
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Prefix {
+    fn matches(&self, url: &Url) -> bool {
+        url.host_str() == Some(self.host.as_str()) && url.path().starts_with(&self.path)
+    }
+}
+
+/// Which pages may be counted.
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    /// Prefixes of pages that may be counted. If empty, any page may.
+    pub prefixes: Vec<Prefix>,
+
+    /// Max length of a page key.
+    pub max_key_len: usize,
+}
+
+impl Default for Allowlist {
+    fn default() -> Self {
+        Allowlist {
+            prefixes: Vec::new(),
+            max_key_len: DEFAULT_MAX_KEY_LEN,
+        }
+    }
+}
+
+impl Allowlist {
+    /// Check that a canonical page URL may be counted, and get its key.
+    pub fn check(&self, url: Url) -> Result<String, Rejection> {
+        if !matches!(url.scheme(), "http" | "https")
+            || url.host_str().is_none()
+            || !url.username().is_empty()
+            || url.password().is_some()
+        {
+            return Err(Rejection::Invalid);
+        }
+        if !self.prefixes.is_empty() && !self.prefixes.iter().any(|p| p.matches(&url)) {
+            return Err(Rejection::NotAllowed);
+        }
+        let key = String::from(url);
+        if key.len() > self.max_key_len {
+            return Err(Rejection::TooLong(key.len()));
+        }
+        Ok(key)
+    }
+}
+
+/// Parse a host alias, as `ALIAS=HOST`.
+pub fn parse_host_alias(s: &str) -> Result<(String, String), String> {
+    let (alias, host) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid host alias {s:?}, must be ALIAS=HOST"))?;
+    let parse = |h: &str| {
+        url::Host::parse(h)
+            .map(|h| h.to_string())
+            .map_err(|e| format!("invalid host {h:?}: {e}"))
+    };
+    Ok((parse(alias)?, parse(host)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_host_alias, Allowlist, Rejection, Rules};
+
+    fn key(rules: &Rules, url: &str) -> String {
+        rules.key(&url::Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn keeps_old_keys_by_default() {
+        let rules = Rules::default();
+        for (url, want) in [
+            ("https://x.test/a", "https://x.test/a"),
+            ("https://X.test/a?page=2#comments", "https://x.test/a"),
+            ("https://x.test/a/", "https://x.test/a/"),
+            ("http://x.test/a/index.html", "http://x.test/a/index.html"),
+        ] {
+            assert_eq!(key(&rules, url), want, "{url}");
+        }
+    }
+
+    #[test]
+    fn canonicalizes_keys() {
+        let rules = Rules {
+            merge_schemes: true,
+            strip_trailing_slash: true,
+            index_files: vec!["index.html".to_owned()],
+            query_params: vec!["page".to_owned(), "lang".to_owned()],
+            host_aliases: [parse_host_alias("www.x.test=x.test").unwrap()].into(),
+        };
+        for url in [
+            "https://x.test/a",
+            "https://x.test/a/",
+            "https://x.test/a#comments",
+            "https://X.test/a/index.html",
+            "http://x.test/a",
+            "https://www.x.test/a?utm_source=feed",
+        ] {
+            assert_eq!(key(&rules, url), "https://x.test/a", "{url}");
+        }
+        for (url, want) in [
+            ("https://x.test/", "https://x.test/"),
+            ("https://x.test/index.html", "https://x.test/"),
+            ("http://x.test:8080/a/", "https://x.test:8080/a"),
+            ("https://x.test/a?page=2", "https://x.test/a?page=2"),
+            (
+                "https://x.test/a?page=2&utm_source=feed&lang=en",
+                "https://x.test/a?lang=en&page=2",
+            ),
+            ("https://x.test/a/index.htm", "https://x.test/a/index.htm"),
+            ("https://y.test/a", "https://y.test/a"),
+        ] {
+            assert_eq!(key(&rules, url), want, "{url}");
+        }
+    }
+
+    #[test]
+    fn checks_allowlist() {
+        let check = |list: &Allowlist, url: &str| list.check(url::Url::parse(url).unwrap());
+
+        let list = Allowlist::default();
+        assert!(check(&list, "https://anything.test/page").is_ok());
+        for url in [
+            "ftp://x.test/a",
+            "data:text/plain,hello",
+            "https://user@x.test/a",
+            "https://:pass@x.test/a",
+        ] {
+            assert_eq!(check(&list, url), Err(Rejection::Invalid), "{url}");
+        }
+        let long = format!("https://x.test/{}", "a".repeat(super::DEFAULT_MAX_KEY_LEN));
+        assert!(matches!(check(&list, &long), Err(Rejection::TooLong(_))));
+
+        let list = Allowlist {
+            prefixes: vec!["X.test".parse().unwrap(), "y.test/blog/".parse().unwrap()],
+            max_key_len: 30,
+        };
+        for (url, want) in [
+            ("https://x.test/", Ok("https://x.test/".to_owned())),
+            ("http://x.test/a", Ok("http://x.test/a".to_owned())),
+            (
+                "https://y.test/blog/a",
+                Ok("https://y.test/blog/a".to_owned()),
+            ),
+            ("https://y.test/blog", Err(Rejection::NotAllowed)),
+            ("https://y.test/", Err(Rejection::NotAllowed)),
+            ("https://www.x.test/", Err(Rejection::NotAllowed)),
+            ("https://z.test/", Err(Rejection::NotAllowed)),
+            (
+                "https://x.test/aaaaaaaaaaaaaaaa",
+                Err(Rejection::TooLong(31)),
+            ),
+        ] {
+            assert_eq!(check(&list, url), want, "{url}");
+        }
+        assert!("".parse::<super::Prefix>().is_err());
+        assert!("/blog/".parse::<super::Prefix>().is_err());
+    }
+
+    #[test]
+    fn parses_host_aliases() {
+        assert_eq!(
+            parse_host_alias("WWW.example.com=example.com"),
+            Ok(("www.example.com".to_owned(), "example.com".to_owned()))
+        );
+        assert!(parse_host_alias("example.com").is_err());
+        assert!(parse_host_alias("a b=example.com").is_err());
+        assert!(parse_host_alias("example.com=").is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Gossip of per-key counts between livecount instances.
+//!
+//! Every instance periodically sends the counts of its local handles to all
+//! configured peers over UDP. Received counts are added to the local counts
+//! by the registry, and expire if the peer stops sending them.
+//!
+//! Each datagram is plain text. The first line is a header with the protocol
+//! version and how long the counts are valid for, in milliseconds. Each
+//! following line is a count followed by the key:
+//!
+//! ```text
+//! livecount-cluster 1 15000
+//! 3 https://example.com/page
+//! 0 https://example.com/other
+//! ```
+//!
+//! A count of zero means the key went away since the last report.
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, trace, warn};
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+
+use crate::registry::{Registry, CLUSTER_MESSAGES};
+
+const MAGIC: &str = "livecount-cluster";
+const VERSION: &str = "1";
+
+/// Max payload of a gossip datagram. Larger reports are split.
+const MAX_DATAGRAM: usize = 8192;
+
+/// Number of gossip intervals a peer can be silent before its counts expire.
+const TTL_INTERVALS: u32 = 3;
+
+pub async fn bind(listen: SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::bind(listen).await
+}
+
+/// Gossip counts with peers until the socket fails.
+pub async fn run(
+    reg: Arc<Registry>,
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+    interval: Duration,
+) {
+    let socket = Arc::new(socket);
+    let send = send_loop(reg.clone(), socket.clone(), &peers, interval);
+    let recv = recv_loop(reg, socket.clone(), &peers);
+    tokio::join!(send, recv);
+}
+
+async fn send_loop(
+    reg: Arc<Registry>,
+    socket: Arc<UdpSocket>,
+    peers: &[SocketAddr],
+    interval: Duration,
+) {
+    let ttl = interval * TTL_INTERVALS;
+    let mut last_sent = HashSet::new();
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        let Some(mut counts) = reg.local_counts().await else {
+            warn!("Registry gone, stopping cluster gossip");
+            return;
+        };
+        let current: HashSet<String> = counts.keys().cloned().collect();
+        for key in last_sent.difference(&current) {
+            counts.insert(key.clone(), 0);
+        }
+        last_sent = current;
+
+        for datagram in encode(&counts, ttl) {
+            for peer in peers {
+                match socket.send_to(&datagram, peer).await {
+                    Ok(_) => CLUSTER_MESSAGES.with_label_values(&["tx", "ok"]).inc(),
+                    Err(e) => {
+                        debug!("Failed to send gossip to {peer}: {e}");
+                        CLUSTER_MESSAGES.with_label_values(&["tx", "error"]).inc();
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn recv_loop(reg: Arc<Registry>, socket: Arc<UdpSocket>, peers: &[SocketAddr]) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                // Errors such as ICMP port unreachable from a peer that's
+                // down are reported here, and are not fatal.
+                debug!("Failed to receive gossip: {e}");
+                CLUSTER_MESSAGES.with_label_values(&["rx", "error"]).inc();
+                continue;
+            }
+        };
+        if !peers.contains(&peer) {
+            warn!("Ignoring gossip from unknown peer {peer}");
+            CLUSTER_MESSAGES
+                .with_label_values(&["rx", "unknown_peer"])
+                .inc();
+            continue;
+        }
+        let Some((counts, ttl)) = decode(&buf[..len]) else {
+            warn!("Ignoring bad gossip from {peer}");
+            CLUSTER_MESSAGES.with_label_values(&["rx", "bad"]).inc();
+            continue;
+        };
+        trace!("Got {} counts from {peer}", counts.len());
+        CLUSTER_MESSAGES.with_label_values(&["rx", "ok"]).inc();
+        if reg.peer_counts(peer, counts, ttl).await.is_err() {
+            warn!("Registry gone, stopping cluster gossip");
+            return;
+        }
+    }
+}
+
+/// Encode counts into one or more datagrams.
+fn encode(counts: &HashMap<String, u64>, ttl: Duration) -> Vec<Vec<u8>> {
+    let header = format!("{MAGIC} {VERSION} {}\n", ttl.as_millis());
+    let mut out = Vec::new();
+    let mut cur = header.clone().into_bytes();
+    for (key, count) in counts {
+        let line = format!("{count} {key}\n");
+        if header.len() + line.len() > MAX_DATAGRAM {
+            warn!("Not gossiping key too long for a datagram: {key}");
+            continue;
+        }
+        if cur.len() + line.len() > MAX_DATAGRAM {
+            out.push(std::mem::replace(&mut cur, header.clone().into_bytes()));
+        }
+        cur.extend_from_slice(line.as_bytes());
+    }
+    if cur.len() > header.len() || out.is_empty() {
+        out.push(cur);
+    }
+    out
+}
+
+/// Decode a datagram into counts and how long they're valid for.
+fn decode(data: &[u8]) -> Option<(HashMap<String, u64>, Duration)> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut lines = text.lines();
+    let mut header = lines.next()?.split(' ');
+    if header.next()? != MAGIC || header.next()? != VERSION {
+        return None;
+    }
+    let ttl = Duration::from_millis(header.next()?.parse().ok()?);
+    let mut counts = HashMap::new();
+    for line in lines {
+        let (count, key) = line.split_once(' ')?;
+        if key.is_empty() {
+            return None;
+        }
+        counts.insert(key.to_owned(), count.parse().ok()?);
+    }
+    Some((counts, ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use tokio::time::error::Elapsed;
+    use tokio::time::Duration;
+
+    use super::{bind, decode, encode, run, MAX_DATAGRAM};
+    use crate::registry::{Handle, Registry};
+
+    #[test]
+    fn encodes_and_decodes_counts() {
+        let counts = HashMap::from([
+            ("https://example.test/a".to_string(), 3),
+            ("https://example.test/b c".to_string(), 0),
+        ]);
+        let datagrams = encode(&counts, Duration::from_secs(15));
+        assert_eq!(datagrams.len(), 1);
+        let (decoded, ttl) = decode(&datagrams[0]).unwrap();
+        assert_eq!(decoded, counts);
+        assert_eq!(ttl, Duration::from_secs(15));
+
+        assert!(decode(b"something else 1 1000\n").is_none());
+        assert!(decode(b"livecount-cluster 1 1000\nnotanumber key\n").is_none());
+    }
+
+    #[test]
+    fn splits_large_reports() {
+        let counts: HashMap<_, _> = (0..1000)
+            .map(|n| (format!("https://example.test/page/{n}"), n))
+            .collect();
+        let datagrams = encode(&counts, Duration::from_secs(1));
+        assert!(datagrams.len() > 1);
+
+        let mut decoded = HashMap::new();
+        for datagram in datagrams {
+            assert!(datagram.len() <= MAX_DATAGRAM);
+            decoded.extend(decode(&datagram).unwrap().0);
+        }
+        assert_eq!(decoded, counts);
+    }
+
+    #[tokio::test]
+    async fn sums_counts_across_instances() {
+        let sock_a = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let sock_b = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr_a = sock_a.local_addr().unwrap();
+        let addr_b = sock_b.local_addr().unwrap();
+        let reg_a = Arc::new(Registry::new());
+        let reg_b = Arc::new(Registry::new());
+        let interval = Duration::from_millis(50);
+        tokio::spawn(run(reg_a.clone(), sock_a, vec![addr_b], interval));
+        tokio::spawn(run(reg_b.clone(), sock_b, vec![addr_a], interval));
+
+        let mut ha = reg_a.register("foo").await.unwrap();
+        let mut hb = reg_b.register("foo").await.unwrap();
+        wait_for(&mut ha, 2).await.unwrap();
+        wait_for(&mut hb, 2).await.unwrap();
+
+        let hb2 = reg_b.register("foo").await.unwrap();
+        wait_for(&mut ha, 3).await.unwrap();
+
+        hb2.close().await;
+        wait_for(&mut ha, 2).await.unwrap();
+    }
+
+    async fn wait_for(h: &mut Handle, want: u64) -> Result<(), Elapsed> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while h.next().await.unwrap() != want {}
+        })
+        .await
+    }
+}
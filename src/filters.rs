@@ -1,41 +1,87 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::LazyLock;
 
-use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
-use log::{debug, error, info, trace, warn};
-use tokio::time::Duration;
-use warp::http::header::HeaderMap;
+use log::{debug, warn};
+use warp::http::header::{HeaderMap, HeaderValue};
 use warp::http::StatusCode;
 use warp::reply::Response;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 use warp::Reply;
 
-use crate::registry::Registry;
-use crate::registry::{PING_LATENCY, TIMEOUTS, UPDATES_SENT, WS_RX_TYPE};
+use crate::badge;
+use crate::canonical;
+use crate::handoff;
+use crate::keepalive::Keepalive;
+use crate::limits::{self, Limiter};
+use crate::origin;
+use crate::registry::{
+    Count, CountMode, Peak, Registry, Sample, Viewer, CONNECTION_REJECTIONS, PAGE_REJECTIONS,
+};
+use crate::session::{self, Frame, Protocol, SseEvent, TopReply};
+use crate::token;
 
-static THE_PAST: LazyLock<std::time::Instant> = LazyLock::new(std::time::Instant::now);
+/// Max size of a message from a client. Clients are not expected to send
+/// anything but pings, pongs and subscription commands.
+const MAX_WS_MESSAGE_SIZE: usize = 64 * 1024;
 
-mod static_assert {
-    /// This asserts that ping life is at least `MIN_PING_SECS` seconds before end of
-    /// websocket life.
-    const _MUST_WORK: u64 = super::MAX_WS_LIFE_PING_SECS - super::_MIN_PING_SECS;
-}
+/// Cookie with the visitor ID, when counting visitors.
+const VISITOR_COOKIE: &str = "livecount_id";
+
+/// How long browsers keep the visitor cookie.
+const VISITOR_COOKIE_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Max length of a visitor ID from a cookie or query parameter.
+const MAX_VISITOR_ID_LEN: usize = 64;
+
+/// Number of top pages returned by default, and at most.
+const DEFAULT_TOP_PAGES: usize = 20;
+const MAX_TOP_PAGES: usize = 100;
+
+/// Max number of pages in one batch count request.
+const MAX_BATCH_COUNTS: usize = 100;
+
+/// Cache-Control of count replies. Counts change all the time, so caches
+/// must always check.
+const COUNT_CACHE_CONTROL: &str = "no-cache";
+
+/// The widget script, and the version of it. The version is bumped when
+/// the script's options change incompatibly.
+const WIDGET_JS: &str = include_str!("widget.js");
+const WIDGET_VERSION: u32 = 1;
+
+/// Cache-Control of the widget script.
+const WIDGET_CACHE_CONTROL: &str = "max-age=3600";
 
-const _MIN_PING_SECS: u64 = 10;
+/// Cache-Control of badges. Image proxies, like the one GitHub puts in front
+/// of README images, may keep them for this long.
+const BADGE_CACHE_CONTROL: &str = "max-age=10, must-revalidate";
 
-/// Max lifetime of an idle websocket.
-const MAX_WS_LIFE_SECS: u64 = 600; // 10 minutes.
-const MAX_WS_LIFE: Duration = Duration::from_secs(MAX_WS_LIFE_SECS);
+/// Filter settings.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Secret that websocket clients pass as the `observer` query parameter
+    /// to get counts without being counted. If unset, there are no observers.
+    pub observer_secret: Option<String>,
 
-/// Websocket lifetime after which a ping is sent.
-const MAX_WS_LIFE_PING_SECS: u64 = MAX_WS_LIFE_SECS - 60;
-const MAX_WS_LIFE_PING: Duration = Duration::from_secs(MAX_WS_LIFE_PING_SECS);
+    /// Origins allowed to count pages other than their own, and to read
+    /// their counts cross-origin.
+    pub origins: origin::Policy,
 
-/// Timeout for sending websocket message.
-const MAX_WS_SEND_TIME: Duration = Duration::from_secs(5);
+    /// How page URLs are turned into the keys they are counted under.
+    pub canonical: canonical::Rules,
+
+    /// Which pages may be counted.
+    pub pages: canonical::Allowlist,
+
+    /// Key of the tokens that counting requests must have as the `t` query
+    /// parameter. If unset, no tokens are needed.
+    pub token_key: Option<token::Key>,
+
+    /// Limits on websocket connections.
+    pub limits: limits::Limits,
+}
 
 #[derive(Debug)]
 enum WsRequestError {
@@ -44,14 +90,44 @@ enum WsRequestError {
     MissingOrigin,
     InvalidOrigin(url::ParseError),
     OriginMismatch { origin: String, url: String },
+    InvalidTopCount(String),
+    InvalidPollCount(String),
+    TooManyPages(usize),
+    InvalidBadgeOption(String),
+    InvalidPage(String),
+    PageTooLong(usize),
+    PageNotAllowed(String),
+    MissingToken,
+    InvalidToken,
+    ExpiredToken,
+    InvalidObserverSecret,
+    TooManyConnections,
+    TooManyConnectionsFromAddr,
+    ConnectingTooOften,
 }
 
 impl WsRequestError {
     fn status(&self) -> StatusCode {
         match self {
-            Self::MissingLocation | Self::InvalidLocation(_) => StatusCode::BAD_REQUEST,
-            Self::MissingOrigin | Self::InvalidOrigin(_) | Self::OriginMismatch { .. } => {
-                StatusCode::FORBIDDEN
+            Self::MissingLocation
+            | Self::InvalidLocation(_)
+            | Self::InvalidTopCount(_)
+            | Self::InvalidPollCount(_)
+            | Self::TooManyPages(_)
+            | Self::InvalidBadgeOption(_)
+            | Self::InvalidPage(_)
+            | Self::PageTooLong(_) => StatusCode::BAD_REQUEST,
+            Self::MissingOrigin
+            | Self::InvalidOrigin(_)
+            | Self::OriginMismatch { .. }
+            | Self::PageNotAllowed(_)
+            | Self::MissingToken
+            | Self::InvalidToken
+            | Self::ExpiredToken
+            | Self::InvalidObserverSecret => StatusCode::FORBIDDEN,
+            Self::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyConnectionsFromAddr | Self::ConnectingTooOften => {
+                StatusCode::TOO_MANY_REQUESTS
             }
         }
     }
@@ -63,6 +139,30 @@ impl WsRequestError {
             Self::MissingOrigin => "missing websocket origin",
             Self::InvalidOrigin(_) => "invalid websocket origin",
             Self::OriginMismatch { .. } => "websocket origin does not match page URL",
+            Self::InvalidTopCount(_) => "invalid number of top pages",
+            Self::InvalidPollCount(_) => "invalid count or version",
+            Self::TooManyPages(_) => "too many page URLs",
+            Self::InvalidBadgeOption(_) => "invalid badge option",
+            Self::InvalidPage(_) => "livecount page URL can't be counted",
+            Self::PageTooLong(_) => "livecount page URL too long",
+            Self::PageNotAllowed(_) => "livecount page not allowed",
+            Self::MissingToken => "missing livecount page token",
+            Self::InvalidToken => "invalid livecount page token",
+            Self::ExpiredToken => "expired livecount page token",
+            Self::InvalidObserverSecret => "invalid observer secret",
+            Self::TooManyConnections => "too many connections",
+            Self::TooManyConnectionsFromAddr => "too many connections from your address",
+            Self::ConnectingTooOften => "connecting too often",
+        }
+    }
+}
+
+impl From<limits::Rejection> for WsRequestError {
+    fn from(rejection: limits::Rejection) -> Self {
+        match rejection {
+            limits::Rejection::Total => Self::TooManyConnections,
+            limits::Rejection::PerAddr => Self::TooManyConnectionsFromAddr,
+            limits::Rejection::Rate => Self::ConnectingTooOften,
         }
     }
 }
@@ -77,11 +177,25 @@ impl std::fmt::Display for WsRequestError {
             Self::OriginMismatch { origin, url } => {
                 write!(f, "Origin {origin:?} does not match page URL {url:?}")
             }
+            Self::InvalidTopCount(n) => write!(f, "invalid n query parameter {n:?}"),
+            Self::InvalidPollCount(n) => write!(f, "invalid count or version {n:?}"),
+            Self::TooManyPages(n) => write!(f, "{n} l query parameters"),
+            Self::InvalidBadgeOption(name) => write!(f, "invalid {name} query parameter"),
+            Self::InvalidPage(url) => write!(f, "page URL {url:?} can't be counted"),
+            Self::PageTooLong(len) => write!(f, "page URL of {len} bytes"),
+            Self::PageNotAllowed(url) => write!(f, "page {url:?} not allowed"),
+            Self::MissingToken => write!(f, "missing t query parameter"),
+            Self::InvalidToken => write!(f, "invalid t query parameter"),
+            Self::ExpiredToken => write!(f, "expired t query parameter"),
+            Self::InvalidObserverSecret => write!(f, "invalid observer secret"),
+            Self::TooManyConnections => write!(f, "too many connections"),
+            Self::TooManyConnectionsFromAddr => write!(f, "too many connections from address"),
+            Self::ConnectingTooOften => write!(f, "address connecting too often"),
         }
     }
 }
 
-fn websocket_error_response(err: &WsRequestError) -> Response {
+fn request_error_response(err: &WsRequestError) -> Response {
     warp::reply::with_status(err.client_message(), err.status()).into_response()
 }
 
@@ -89,19 +203,169 @@ fn livecount_url_from_query(
     querymap: &HashMap<String, String>,
 ) -> Result<url::Url, WsRequestError> {
     let location = querymap.get("l").ok_or(WsRequestError::MissingLocation)?;
-    let mut url = url::Url::parse(location).map_err(WsRequestError::InvalidLocation)?;
-    url.set_query(None);
-    Ok(url)
+    livecount_url(location)
+}
+
+/// Parse a page URL. It is counted under the key from
+/// [`canonical::Rules::key`], but origins are checked against the URL as is.
+fn livecount_url(location: &str) -> Result<url::Url, WsRequestError> {
+    url::Url::parse(location).map_err(WsRequestError::InvalidLocation)
+}
+
+/// Get the key a page is counted under, if it may be counted.
+fn page_key(config: &Config, url: &url::Url) -> Result<String, WsRequestError> {
+    let url = config.canonical.canonicalize(url);
+    config.pages.check(url.clone()).map_err(|rejection| {
+        PAGE_REJECTIONS
+            .with_label_values(&[rejection.reason()])
+            .inc();
+        match rejection {
+            canonical::Rejection::Invalid => WsRequestError::InvalidPage(url.into()),
+            canonical::Rejection::TooLong(len) => WsRequestError::PageTooLong(len),
+            canonical::Rejection::NotAllowed => WsRequestError::PageNotAllowed(url.into()),
+        }
+    })
+}
+
+/// Get the number of pages and optional host filter of a top pages request.
+fn top_params_from_query(
+    querymap: &HashMap<String, String>,
+) -> Result<(usize, Option<&str>), WsRequestError> {
+    let n = match querymap.get("n") {
+        None => DEFAULT_TOP_PAGES,
+        Some(n) => match n.parse() {
+            Ok(n) if (1..=MAX_TOP_PAGES).contains(&n) => n,
+            _ => return Err(WsRequestError::InvalidTopCount(n.to_owned())),
+        },
+    };
+    Ok((n, querymap.get("host").map(String::as_str)))
+}
+
+/// Get all page URLs of a count request, from its repeated `l` parameters.
+fn livecount_urls_from_query(query: &str) -> Result<Vec<url::Url>, WsRequestError> {
+    let urls = url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(name, _)| name == "l")
+        .map(|(_, location)| livecount_url(&location))
+        .collect::<Result<Vec<_>, _>>()?;
+    match urls.len() {
+        0 => Err(WsRequestError::MissingLocation),
+        n if n > MAX_BATCH_COUNTS => Err(WsRequestError::TooManyPages(n)),
+        _ => Ok(urls),
+    }
+}
+
+/// Get the count and version a long-polling client last got, if any.
+fn seen_count_from_query(
+    querymap: &HashMap<String, String>,
+) -> Result<Option<Count>, WsRequestError> {
+    let parse = |name| {
+        querymap
+            .get(name)
+            .map(|v| {
+                v.parse::<u64>()
+                    .map_err(|_| WsRequestError::InvalidPollCount(v.to_owned()))
+            })
+            .transpose()
+    };
+    Ok(match (parse("count")?, parse("version")?) {
+        (Some(count), Some(version)) => Some(Count { count, version }),
+        _ => None,
+    })
+}
+
+fn valid_visitor_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_VISITOR_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn visitor_cookie(heads: &HeaderMap) -> Option<&str> {
+    heads
+        .get_all(warp::http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == VISITOR_COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// Make up a new visitor ID.
+///
+/// It only needs to be unique, not secret.
+fn new_visitor_id() -> String {
+    use std::hash::{BuildHasher, RandomState};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let a = RandomState::new().hash_one((n, time));
+    let b = RandomState::new().hash_one((time, n));
+    format!("{a:016x}{b:016x}")
+}
+
+/// Get the visitor ID of a websocket request, from the `c` query parameter
+/// or the visitor cookie. If there is no valid ID, a new one is made up and
+/// returned as the second value, to be set as a cookie.
+fn visitor_from_request(
+    heads: &HeaderMap,
+    querymap: &HashMap<String, String>,
+) -> (String, Option<String>) {
+    let id = querymap
+        .get("c")
+        .map(String::as_str)
+        .or_else(|| visitor_cookie(heads))
+        .filter(|id| valid_visitor_id(id));
+    match id {
+        Some(id) => (id.to_owned(), None),
+        None => {
+            let id = new_visitor_id();
+            (id.clone(), Some(id))
+        }
+    }
+}
+
+/// Compare secrets, taking the same time no matter where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn validate_observer_secret(configured: Option<&str>, secret: &str) -> Result<(), WsRequestError> {
+    match configured {
+        Some(configured) if constant_time_eq(configured.as_bytes(), secret.as_bytes()) => Ok(()),
+        _ => Err(WsRequestError::InvalidObserverSecret),
+    }
+}
+
+/// Get the origin of a request, from the `Origin` header or else the
+/// `Referer` header. Browsers leave out `Origin` on same-origin `GET`
+/// requests, such as those of `EventSource`.
+fn request_origin(heads: &HeaderMap) -> Option<String> {
+    if let Some(origin) = heads.get(warp::http::header::ORIGIN) {
+        return origin.to_str().ok().map(str::to_owned);
+    }
+    let referer = heads.get(warp::http::header::REFERER)?.to_str().ok()?;
+    let origin = url::Url::parse(referer).ok()?.origin();
+    origin.is_tuple().then(|| origin.ascii_serialization())
 }
 
-fn validate_origin(url: &url::Url, origin: Option<&str>) -> Result<(), WsRequestError> {
+fn validate_origin(
+    policy: &origin::Policy,
+    url: &url::Url,
+    origin: Option<&str>,
+) -> Result<(), WsRequestError> {
     let origin = origin.ok_or(WsRequestError::MissingOrigin)?;
     let origin_url = url::Url::parse(origin).map_err(WsRequestError::InvalidOrigin)?;
 
-    if url.scheme() == origin_url.scheme()
-        && url.host_str() == origin_url.host_str()
-        && url.port_or_known_default() == origin_url.port_or_known_default()
-    {
+    if policy.allows(&origin_url, url) {
         return Ok(());
     }
 
@@ -111,37 +375,110 @@ fn validate_origin(url: &url::Url, origin: Option<&str>) -> Result<(), WsRequest
     })
 }
 
+/// Get the origin to allow reading a reply cross-origin, if any.
+///
+/// Requests for pages, in `l` query parameters, follow the same policy as
+/// counting them. Other requests are allowed for origins with a rule.
+fn cors_allowed_origin(
+    policy: &origin::Policy,
+    origin: Option<&str>,
+    query: &str,
+) -> Option<HeaderValue> {
+    let origin = origin?;
+    let origin_url = url::Url::parse(origin).ok()?;
+    let pages = url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(name, _)| name == "l")
+        .map(|(_, location)| livecount_url(&location).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let allowed = if pages.is_empty() {
+        policy.allows_any(&origin_url)
+    } else {
+        pages.iter().all(|page| policy.allows(&origin_url, page))
+    };
+    allowed
+        .then(|| HeaderValue::from_str(origin).ok())
+        .flatten()
+}
+
 pub fn livecount(
     reg: Arc<Registry>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    livecount_with_config(reg, Config::default())
+}
+
+pub fn livecount_with_config(
+    reg: Arc<Registry>,
+    config: Config,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     debug!("livecount()");
-    livecount_index()
-        .or(livecount_ws(reg))
-        .with(warp::cors().allow_any_origin())
+    let config = Arc::new(config);
+    let keepalive = session::keepalive();
+    let limiter = Limiter::new(config.limits.clone());
+    let top = session::TopFeed::new(reg.clone(), MAX_TOP_PAGES);
+    let cors_config = config.clone();
+    let cors = warp::header::optional::<String>("origin")
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |origin: Option<String>, query: String| {
+            cors_allowed_origin(&cors_config.origins, origin.as_deref(), &query)
+        });
+    let routes = livecount_index()
+        .or(livecount_widget())
+        .or(livecount_ws(
+            reg.clone(),
+            keepalive.clone(),
+            config.clone(),
+            limiter.clone(),
+        ))
+        .or(livecount_sse(
+            reg.clone(),
+            keepalive.clone(),
+            config.clone(),
+            limiter.clone(),
+        ))
+        .or(livecount_poll(reg.clone(), config.clone(), limiter.clone()))
+        .or(livecount_history(reg.clone(), config.clone()))
+        .or(livecount_peaks(reg.clone(), config.clone()))
+        .or(livecount_count(reg.clone(), config.clone()))
+        .or(livecount_badge(reg.clone(), config.clone()))
+        .or(livecount_top_ws(top, keepalive.clone(), limiter.clone()))
+        .or(livecount_multi_ws(reg.clone(), keepalive, config, limiter))
+        .or(livecount_top(reg.clone()))
+        .or(livecount_ready(reg));
+    cors.and(routes).map(|allowed: Option<HeaderValue>, reply| {
+        let mut response = warp::Reply::into_response(reply);
+        let headers = response.headers_mut();
+        headers.append(warp::http::header::VARY, HeaderValue::from_static("origin"));
+        if let Some(origin) = allowed {
+            headers.insert(warp::http::header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        response
+    })
 }
 
-/// Send a message on a websocket, with a timeout.
-///
-/// On error, return a one-word string suitable for putting in the prometheus
-/// metric.
-async fn websocket_send(
-    tx: &mut SplitSink<WebSocket, Message>,
-    msg: Message,
-) -> std::result::Result<(), String> {
-    let timeout = tokio::time::sleep(MAX_WS_SEND_TIME);
-    tokio::pin!(timeout);
-    tokio::select! {
-        _ = timeout => {
-            Err("timeout".to_string())
-        },
-        r = tx.send(msg) => {
-            match r {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    info!("Failed to send websocket message: {e}");
-                    Err(e.to_string())
-                }
-            }
+impl From<Message> for Frame {
+    fn from(m: Message) -> Self {
+        if m.is_text() {
+            Frame::Text(m.to_str().unwrap_or_default().to_owned())
+        } else if m.is_ping() {
+            Frame::Ping(m.into_bytes())
+        } else if m.is_pong() {
+            Frame::Pong(m.into_bytes())
+        } else if m.is_close() {
+            Frame::Close
+        } else {
+            Frame::Binary(m.into_bytes())
+        }
+    }
+}
+
+impl From<Frame> for Message {
+    fn from(f: Frame) -> Self {
+        match f {
+            Frame::Text(t) => Message::text(t),
+            Frame::Binary(b) => Message::binary(b),
+            Frame::Ping(b) => Message::ping(b),
+            Frame::Pong(b) => Message::pong(b),
+            Frame::Close => Message::close(),
         }
     }
 }
@@ -149,220 +486,35 @@ async fn websocket_send(
 async fn livecount_ws_map_upgrade(
     websocket: WebSocket,
     remote: String,
-    url: &url::Url,
+    key: String,
+    viewer: Viewer,
+    protocol: Protocol,
     reg: Arc<Registry>,
+    keepalive: Keepalive,
 ) {
     debug!("livecount_ws_map_upgrade()");
-    debug!("WS upgrade on {url} by {remote}");
-
-    // Split the websocket so that we can give it to separate futures.
-    let (mut tx, mut rx) = websocket.split();
-
-    // See https://biriukov.dev/docs/async-rust-tokio-io/3-tokio-io-patterns/ pattern.
-    let mut handle = reg.register(url.as_str()).await.unwrap();
-
-    // Sleep until connection times out. Gets reset on every incoming activity.
-    let new_sleep: Arc<tokio::sync::Mutex<Option<tokio::time::Instant>>> = Default::default();
-    let new_sleep_renew = || async {
-        (*new_sleep.lock().await) = Some(tokio::time::Instant::now() + MAX_WS_LIFE);
-    };
-    new_sleep_renew().await;
-
-    // Sleep until it's time to send a ping. Also gets reset on activity.
-    let new_sleep_ping: Arc<tokio::sync::Mutex<Option<tokio::time::Instant>>> = Default::default();
-    let new_sleep_ping_renew = || async {
-        (*new_sleep_ping.lock().await) = Some(tokio::time::Instant::now() + MAX_WS_LIFE_PING);
-    };
-    new_sleep_ping_renew().await;
-
-    // TODO: why 10? Once it's more in flight, we terminate the connection.
-    let (to_client_tx, mut to_client_rx) = tokio::sync::mpsc::channel(10);
-
-    // Async that gets updates from the registry.
-    let from_registry = async {
-        while let Some(msg) = handle.next().await {
-            // If client has become unresponsive, we'll close the connection
-            // after 10 in flight messages.
-            //
-            // We don't want to block here since it can deadlock the register,
-            // as we're then no longer reading from the handle in the loop.
-            if let Err(e) = to_client_tx.try_send(Message::text(format!("{msg}"))) {
-                error!("Failed to send to client-sender: {e:?}");
-                return Err("failed to send to client-sender".to_owned());
-            }
-        }
-        debug!("Registry closing");
-        Ok(())
-    };
-
-    // Async that actually sends on websocket.
-    let to_client = async {
-        while let Some(msg) = to_client_rx.recv().await {
-            match websocket_send(&mut tx, msg).await {
-                Err(e) => {
-                    warn!("Error sending on websocket: {e}");
-                    UPDATES_SENT.with_label_values(&["data", &e]).inc();
-                    return Err("sending on websocket".to_owned());
-                }
-                Ok(_) => {
-                    UPDATES_SENT.with_label_values(&["data", "ok"]).inc();
-                }
-            }
-        }
-        debug!("Nothing left to send to client");
-        Ok(())
-    };
-
-    // Async that reads from client.
-    let from_client = async {
-        loop {
-            let wsmsg = rx.next().await;
-            let now = (std::time::Instant::now() - *THE_PAST).as_nanos();
-            match wsmsg {
-                None => {
-                    debug!("Got None message, disconnecting");
-                    WS_RX_TYPE.with_label_values(&["none"]).inc();
-                    return Err::<(), _>("got None message".to_owned());
-                }
-                Some(Ok(ref m)) => {
-                    debug!("Got a message: {m:?}");
-                    new_sleep_ping_renew().await;
-                    if m.is_close() {
-                        debug!("WS Disconnection: {:?}", wsmsg);
-                        WS_RX_TYPE.with_label_values(&["away"]).inc();
-                        return Err("WS disconnection".to_owned());
-                    } else if m.is_ping() {
-                        WS_RX_TYPE.with_label_values(&["ping"]).inc();
-                        new_sleep_renew().await;
-                    } else if m.is_text() {
-                        WS_RX_TYPE.with_label_values(&["text"]).inc();
-                        new_sleep_renew().await;
-                    } else if m.is_binary() {
-                        WS_RX_TYPE.with_label_values(&["binary"]).inc();
-                        new_sleep_renew().await;
-                    } else if m.is_pong() {
-                        WS_RX_TYPE.with_label_values(&["pong"]).inc();
-                        let txt = String::from_utf8_lossy(m.as_bytes());
-                        let stxt: Vec<_> = txt.split(' ').collect();
-                        if stxt.len() != 2 || stxt[0] != "livecount" {
-                            error!("Got pong with bad data: {txt}");
-                        } else {
-                            match stxt[1].parse::<u128>() {
-                                Ok(nanos) if now >= nanos => {
-                                    let rtt = std::time::Duration::from_nanos(
-                                        (now - nanos).try_into().unwrap_or(0),
-                                    );
-                                    let rtt_ms = rtt.as_nanos() as f64 / 1_000_000f64;
-                                    PING_LATENCY.observe(rtt_ms);
-                                    trace!("Ping RTT {rtt:?}");
-                                }
-                                Ok(nanos) => {
-                                    error!("Ping time underflow: {now} < {nanos}");
-                                }
-                                Err(_e) => error!("Got pong with bad data: {txt}"),
-                            }
-                        }
-                        new_sleep_renew().await;
-                    } else {
-                        WS_RX_TYPE.with_label_values(&["unknown"]).inc();
-                        error!("Unknown message type: {wsmsg:?}");
-                    }
-                }
-                Some(Err(e)) => {
-                    WS_RX_TYPE.with_label_values(&[format!("{e}")]).inc();
-                    error!("Error receiving message? {e}");
-                    return Err("error receiving message".to_owned());
-                }
-            };
-        }
-    };
-
-    // Async that triggers a total timeout since last activity.
-    // Any async returning Err will terminate all of them.
-    let f_timeout = async {
-        // Sleep as long as deadline keeps being updated.
-        loop {
-            let deadline = {
-                let mut s = new_sleep.lock().await;
-                match s.take() {
-                    None => break,
-                    Some(news) => news,
-                }
-            };
-            tokio::time::sleep_until(deadline).await;
-        }
-        debug!("Max websocket time exceeded");
-        TIMEOUTS.with_label_values(&["final"]).inc();
-        Err::<(), _>("timeout".to_owned())
-    };
-
-    // Async that triggers sending a ping.
-    let f_timeout_ping = async {
-        loop {
-            // Sleep as long as the deadline keeps getting updated.
-            loop {
-                let deadline = {
-                    let mut s = new_sleep_ping.lock().await;
-                    match s.take() {
-                        None => break,
-                        Some(news) => news,
-                    }
-                };
-                tokio::time::sleep_until(deadline).await;
-            }
-
-            // Send a ping.
-            debug!("Max websocket ping time exceeded. Sending ping.");
-            TIMEOUTS.with_label_values(&["ping"]).inc();
-            let nanos = (std::time::Instant::now() - *THE_PAST).as_nanos();
-            match to_client_tx
-                .send(Message::ping(format!("livecount {nanos}").as_bytes()))
-                .await
-            {
-                Err(e) => {
-                    warn!("Error sending ping on websocket: {e}");
-                    UPDATES_SENT
-                        .with_label_values(&["ping", &format!("{e:?}")])
-                        .inc();
-                    return Err::<(), _>("error sending on websocket".to_owned());
-                }
-                Ok(_) => {
-                    UPDATES_SENT.with_label_values(&["ping", "ok"]).inc();
-                }
-            }
-
-            // We may not need another ping; if the first one is not replied to, why would a second
-            // one? But we don't want to busyloop nor exit the async.
-            new_sleep_ping_renew().await;
-        }
-    };
-
-    // Run all asyncs. If any of them return error, terminate them all.
-    if let Err(e) = tokio::try_join!(
-        from_registry,
-        to_client,
-        from_client,
-        f_timeout,
-        f_timeout_ping
-    ) {
-        debug!("WS asyncs ended with: {e:?}");
-    }
-
-    debug!("WS Terminating");
-    handle.close().await;
+    debug!("WS upgrade on {key} by {remote}");
+    let socket = websocket
+        .with(|f: Frame| futures_util::future::ready(Ok::<_, warp::Error>(Message::from(f))))
+        .map(|m| m.map(Frame::from));
+    session::run(socket, &key, viewer, protocol, &reg, &keepalive).await;
 }
 
+#[allow(clippy::too_many_arguments)]
 fn livecount_ws_map(
     ws: warp::ws::Ws,
     remote: Option<std::net::SocketAddr>,
-    origin: Option<String>,
-    _heads: HeaderMap,
+    heads: HeaderMap,
     querymap: HashMap<String, String>,
     inreg: Arc<Registry>,
+    keepalive: Keepalive,
+    config: Arc<Config>,
+    limiter: &Limiter,
 ) -> Response {
     debug!("livecount_ws_map()");
     let reg = inreg.clone();
     // TODO: smarter x-forwarded-for parsing.
+    let addr = remote;
     let remote = match remote {
         Some(ra) => format!("{:?}", ra),
         None => "unknown".to_string(),
@@ -372,135 +524,1995 @@ fn livecount_ws_map(
         Ok(url) => url,
         Err(err) => {
             warn!("Rejecting websocket request: {err}");
-            return websocket_error_response(&err);
+            return request_error_response(&err);
+        }
+    };
+
+    let origin = heads
+        .get(warp::http::header::ORIGIN)
+        .and_then(|v| v.to_str().ok());
+    if let Err(err) = validate_origin(&config.origins, &url, origin) {
+        warn!("Rejecting websocket request: {err}");
+        return request_error_response(&err);
+    }
+    let key = match page_key(&config, &url) {
+        Ok(key) => key,
+        Err(err) => {
+            warn!("Rejecting websocket request: {err}");
+            return request_error_response(&err);
         }
     };
 
-    if let Err(err) = validate_origin(&url, origin.as_deref()) {
+    let (viewer, new_cookie) = match viewer_from_request(&heads, &querymap, &reg, &config) {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("Rejecting websocket request: {err}");
+            return request_error_response(&err);
+        }
+    };
+    if let Err(err) = validate_token(&config, &key, &querymap, &viewer) {
         warn!("Rejecting websocket request: {err}");
-        return websocket_error_response(&err);
+        return request_error_response(&err);
+    }
+
+    let permit = match acquire_permit(limiter, addr) {
+        Ok(permit) => permit,
+        Err(err) => {
+            debug!("Rejecting websocket request from {remote}: {err}");
+            return request_error_response(&err);
+        }
+    };
+
+    let (protocol, subprotocol) = Protocol::negotiate(
+        heads
+            .get(warp::http::header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok()),
+    );
+
+    let mut response = ws
+        .max_message_size(MAX_WS_MESSAGE_SIZE)
+        .max_frame_size(MAX_WS_MESSAGE_SIZE)
+        .on_upgrade(move |websocket| async move {
+            let _permit = permit;
+            livecount_ws_map_upgrade(websocket, remote, key, viewer, protocol, reg, keepalive)
+                .await;
+        })
+        .into_response();
+    if let Some(subprotocol) = subprotocol {
+        response.headers_mut().insert(
+            warp::http::header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(subprotocol),
+        );
     }
+    if let Some(id) = new_cookie {
+        set_visitor_cookie(&mut response, &id);
+    }
+    response
+}
 
-    ws.on_upgrade(move |websocket| async move {
-        livecount_ws_map_upgrade(websocket, remote, &url, reg).await;
+/// Decide how the client of a counting request is counted. Also returns a
+/// new visitor ID to set as a cookie, if one was made up.
+fn viewer_from_request(
+    heads: &HeaderMap,
+    querymap: &HashMap<String, String>,
+    reg: &Registry,
+    config: &Config,
+) -> Result<(Viewer, Option<String>), WsRequestError> {
+    if let Some(secret) = querymap.get("observer") {
+        validate_observer_secret(config.observer_secret.as_deref(), secret)?;
+        return Ok((Viewer::Observer, None));
+    }
+    Ok(match reg.config().count_mode {
+        CountMode::Handles => (Viewer::Anonymous, None),
+        CountMode::Visitors => {
+            let (visitor, new_cookie) = visitor_from_request(heads, querymap);
+            (Viewer::Visitor(visitor.into()), new_cookie)
+        }
     })
-    .into_response()
+}
+
+/// Check the token of a counting request for the page key `key`, if tokens
+/// are needed. Observers don't need one.
+fn validate_token(
+    config: &Config,
+    key: &str,
+    querymap: &HashMap<String, String>,
+    viewer: &Viewer,
+) -> Result<(), WsRequestError> {
+    let Some(token_key) = &config.token_key else {
+        return Ok(());
+    };
+    if matches!(viewer, Viewer::Observer) {
+        return Ok(());
+    }
+    let token = querymap.get("t").ok_or(WsRequestError::MissingToken)?;
+    token_key
+        .verify(key, token, token::now())
+        .map_err(|e| match e {
+            token::Error::Invalid => WsRequestError::InvalidToken,
+            token::Error::Expired => WsRequestError::ExpiredToken,
+        })
+}
+
+fn set_visitor_cookie(response: &mut Response, id: &str) {
+    let cookie = format!(
+        "{VISITOR_COOKIE}={id}; Path=/livecount; Max-Age={VISITOR_COOKIE_MAX_AGE_SECS}; HttpOnly; SameSite=Lax"
+    );
+    match HeaderValue::from_str(&cookie) {
+        Ok(v) => {
+            response
+                .headers_mut()
+                .insert(warp::http::header::SET_COOKIE, v);
+        }
+        Err(e) => warn!("Failed to set visitor cookie: {e}"),
+    }
 }
 
 fn livecount_ws(
     inreg: Arc<Registry>,
+    keepalive: Keepalive,
+    config: Arc<Config>,
+    limiter: Limiter,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     debug!("livecount_ws()");
     warp::path!("livecount" / "ws")
         .and(warp::ws())
-        .and(warp::addr::remote())
-        .and(warp::header::optional::<String>("origin"))
+        .and(remote_addr())
         .and(warp::filters::header::headers_cloned())
         .and(warp::query::<HashMap<String, String>>())
         .map(
             move |ws: warp::ws::Ws,
                   remote: Option<std::net::SocketAddr>,
-                  origin: Option<String>,
                   heads,
                   querymap: HashMap<String, String>| {
-                livecount_ws_map(ws, remote, origin, heads, querymap, inreg.clone())
+                livecount_ws_map(
+                    ws,
+                    remote,
+                    heads,
+                    querymap,
+                    inreg.clone(),
+                    keepalive.clone(),
+                    config.clone(),
+                    &limiter,
+                )
             },
         )
 }
 
-fn livecount_index() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+/// Get a permit for a connection from `remote`, if it's within the limits.
+/// Should be checked last, so that otherwise rejected requests don't use up
+/// the limits.
+fn acquire_permit(
+    limiter: &Limiter,
+    remote: Option<std::net::SocketAddr>,
+) -> Result<limits::Permit, WsRequestError> {
+    limiter
+        .acquire(remote.map(|ra| ra.ip()))
+        .map_err(|rejection| {
+            CONNECTION_REJECTIONS
+                .with_label_values(&[rejection.reason()])
+                .inc();
+            WsRequestError::from(rejection)
+        })
+}
+
+/// Remote address of a request, also for connections handed off by
+/// [`handoff::serve`].
+fn remote_addr(
+) -> impl Filter<Extract = (Option<std::net::SocketAddr>,), Error = std::convert::Infallible> + Clone
 {
-    debug!("livecount_index()");
-    warp::path!("livecount" / "health")
+    warp::addr::remote()
+        .and(warp::ext::optional::<handoff::RemoteAddr>())
+        .map(
+            |remote: Option<std::net::SocketAddr>, handoff: Option<handoff::RemoteAddr>| {
+                remote.or(handoff.map(|h| h.0))
+            },
+        )
+}
+
+async fn livecount_sse_map(
+    remote: Option<std::net::SocketAddr>,
+    heads: HeaderMap,
+    querymap: HashMap<String, String>,
+    reg: Arc<Registry>,
+    keepalive: Keepalive,
+    config: Arc<Config>,
+    limiter: Limiter,
+) -> Result<Response, warp::Rejection> {
+    let url = match livecount_url_from_query(&querymap) {
+        Ok(url) => url,
+        Err(err) => {
+            warn!("Rejecting SSE request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    if let Err(err) = validate_origin(&config.origins, &url, request_origin(&heads).as_deref()) {
+        warn!("Rejecting SSE request: {err}");
+        return Ok(request_error_response(&err));
+    }
+    let key = match page_key(&config, &url) {
+        Ok(key) => key,
+        Err(err) => {
+            warn!("Rejecting SSE request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let (viewer, new_cookie) = match viewer_from_request(&heads, &querymap, &reg, &config) {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("Rejecting SSE request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    if let Err(err) = validate_token(&config, &key, &querymap, &viewer) {
+        warn!("Rejecting SSE request: {err}");
+        return Ok(request_error_response(&err));
+    }
+    let permit = match acquire_permit(&limiter, remote) {
+        Ok(permit) => permit,
+        Err(err) => {
+            debug!("Rejecting SSE request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let Some(handle) = reg.register_as(&key, viewer).await else {
+        warn!("Failed to register SSE client for {key}");
+        return Ok(
+            warp::reply::with_status("registry not ready", StatusCode::SERVICE_UNAVAILABLE)
+                .into_response(),
+        );
+    };
+    debug!("SSE stream for {key}");
+    let events = session::sse_events(handle, &keepalive).map(move |event| {
+        // The stream holds the permit until the client goes away.
+        let _permit = &permit;
+        Ok::<_, std::convert::Infallible>(match event {
+            SseEvent::Count(count) => warp::sse::Event::default()
+                .event("count")
+                .data(count.to_string()),
+            SseEvent::Ping => warp::sse::Event::default().comment("ping"),
+        })
+    });
+    let mut response = warp::sse::reply(events).into_response();
+    if let Some(id) = new_cookie {
+        set_visitor_cookie(&mut response, &id);
+    }
+    Ok(response)
+}
+
+/// Count updates as Server-Sent Events, for clients that can't use
+/// websockets.
+fn livecount_sse(
+    reg: Arc<Registry>,
+    keepalive: Keepalive,
+    config: Arc<Config>,
+    limiter: Limiter,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "sse")
         .and(warp::get())
-        .map(|| {
-            warp::reply::html(
-                r#"
-<html>
-<head>
-</head>
-<body>
-Live counter: <span id="counter"></span>
-</body>
-<script>
-var socket;
-function reconnect() {
-  let loc = window.location;
-  let scheme = "wss";
-  if (loc.protocol == "http:") {
-    scheme = "ws";
-  }
-  let path = `${scheme}://${loc.host}/livecount/ws?l=${loc}`;
-  socket = new WebSocket(path);
-  socket.addEventListener('open', (event) => {
-    console.log('ws opened');
-  });
-  socket.addEventListener('close', (event) => {
-      console.log('ws closed');
-      reconnect();
-  });
-  socket.addEventListener('message', (event) => {
-    console.log('Message from server ', event.data);
-    document.getElementById("counter").innerHTML = event.data;
-  });
-}
-reconnect();
-//setInterval(() => {  socket.ping();}, 10000);
-</script>
-</html>
-"#,
+        .and(remote_addr())
+        .and(warp::filters::header::headers_cloned())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |remote, heads, querymap| {
+            livecount_sse_map(
+                remote,
+                heads,
+                querymap,
+                reg.clone(),
+                keepalive.clone(),
+                config.clone(),
+                limiter.clone(),
             )
         })
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-
-    use super::{livecount_url_from_query, validate_origin, WsRequestError};
+#[derive(serde::Serialize)]
+struct CountReply {
+    url: String,
+    count: u64,
+}
 
-    #[test]
-    fn rejects_missing_or_invalid_livecount_url() {
-        let querymap = HashMap::new();
-        assert!(matches!(
-            livecount_url_from_query(&querymap),
-            Err(WsRequestError::MissingLocation)
-        ));
+#[derive(serde::Serialize)]
+struct CountsReply {
+    counts: Vec<CountReply>,
+}
 
-        let querymap = HashMap::from([("l".to_string(), "not a url".to_string())]);
-        assert!(matches!(
-            livecount_url_from_query(&querymap),
-            Err(WsRequestError::InvalidLocation(_))
-        ));
+/// Reply with `body`, or with 304 Not Modified if the client already has it.
+///
+/// The ETag is a hash of the body, so it's the same across restarts and
+/// cluster instances running the same version.
+fn etag_reply(
+    body: String,
+    content_type: &'static str,
+    cache_control: &'static str,
+    if_none_match: Option<&str>,
+) -> Response {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::hash::DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+    let matches = if_none_match.is_some_and(|header| {
+        header
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag || tag == "*")
+    });
+    let mut response = if matches {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut response = body.into_response();
+        response.headers_mut().insert(
+            warp::http::header::CONTENT_TYPE,
+            HeaderValue::from_static(content_type),
+        );
+        response
+    };
+    let headers = response.headers_mut();
+    if let Ok(v) = HeaderValue::from_str(&etag) {
+        headers.insert(warp::http::header::ETAG, v);
     }
+    headers.insert(
+        warp::http::header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+    response
+}
 
-    #[test]
-    fn strips_query_from_livecount_url() {
-        let querymap = HashMap::from([(
-            "l".to_string(),
-            "https://example.test/page?cachebust=1".to_string(),
-        )]);
+async fn livecount_count_map(
+    batch: bool,
+    query: String,
+    accept: Option<String>,
+    if_none_match: Option<String>,
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> Result<Response, warp::Rejection> {
+    let urls = match livecount_urls_from_query(&query) {
+        Ok(urls) if !batch && urls.len() > 1 => {
+            let err = WsRequestError::TooManyPages(urls.len());
+            debug!("Rejecting count request: {err}");
+            return Ok(request_error_response(&err));
+        }
+        Ok(urls) => urls,
+        Err(err) => {
+            debug!("Rejecting count request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let keys = match urls
+        .iter()
+        .map(|url| page_key(&config, url))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(keys) => keys,
+        Err(err) => {
+            debug!("Rejecting count request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let Some(counts) = reg.counts(&keys).await else {
+        return Ok(
+            warp::reply::with_status("registry not ready", StatusCode::SERVICE_UNAVAILABLE)
+                .into_response(),
+        );
+    };
+    let mut counts: Vec<CountReply> = keys
+        .into_iter()
+        .zip(counts)
+        .map(|(url, count)| CountReply {
+            url,
+            count: count.count,
+        })
+        .collect();
 
-        let url = livecount_url_from_query(&querymap).unwrap();
-        assert_eq!(url.as_str(), "https://example.test/page");
-    }
+    let format = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "format")
+        .map(|(_, format)| format.into_owned());
+    let text = match format.as_deref() {
+        Some(format) => format == "text",
+        None => accept.as_deref().is_some_and(prefers_text),
+    };
+    let if_none_match = if_none_match.as_deref();
+    let mut reply = match (text, batch) {
+        (true, false) => etag_reply(
+            counts[0].count.to_string(),
+            "text/plain; charset=utf-8",
+            COUNT_CACHE_CONTROL,
+            if_none_match,
+        ),
+        (true, true) => {
+            let body = counts
+                .iter()
+                .map(|c| format!("{} {}\n", c.count, c.url))
+                .collect();
+            etag_reply(
+                body,
+                "text/plain; charset=utf-8",
+                COUNT_CACHE_CONTROL,
+                if_none_match,
+            )
+        }
+        (false, false) => match serde_json::to_string(&counts.swap_remove(0)) {
+            Ok(body) => etag_reply(body, "application/json", COUNT_CACHE_CONTROL, if_none_match),
+            Err(e) => {
+                warn!("Failed to encode count: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        },
+        (false, true) => match serde_json::to_string(&CountsReply { counts }) {
+            Ok(body) => etag_reply(body, "application/json", COUNT_CACHE_CONTROL, if_none_match),
+            Err(e) => {
+                warn!("Failed to encode counts: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        },
+    };
+    // The format depends on the Accept header, so caches must tell them
+    // apart.
+    reply
+        .headers_mut()
+        .append(warp::http::header::VARY, HeaderValue::from_static("accept"));
+    Ok(reply)
+}
 
-    #[test]
-    fn validates_origin_against_livecount_url() {
-        let url = url::Url::parse("https://example.test:443/page").unwrap();
+/// Whether an Accept header prefers `text/plain` to `application/json`.
+///
+/// Each type gets the quality of the most specific media range matching it.
+/// Ties go to JSON, the default.
+fn prefers_text(accept: &str) -> bool {
+    // Quality and specificity of the best match so far, per type.
+    let mut text = (0.0, 0);
+    let mut json = (0.0, 0);
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let Some((kind, sub)) = media.split_once('/') else {
+            continue;
+        };
+        for (best, want_kind, want_sub) in [
+            (&mut text, "text", "plain"),
+            (&mut json, "application", "json"),
+        ] {
+            let specificity = match (kind, sub) {
+                ("*", "*") => 1,
+                (k, "*") if k == want_kind => 2,
+                (k, s) if k == want_kind && s == want_sub => 3,
+                _ => continue,
+            };
+            if specificity > best.1 {
+                *best = (q, specificity);
+            }
+        }
+    }
+    text.0 > json.0
+}
 
-        assert!(validate_origin(&url, Some("https://example.test")).is_ok());
-        assert!(matches!(
-            validate_origin(&url, None),
-            Err(WsRequestError::MissingOrigin)
-        ));
+/// Current counts of pages, without being counted. `/livecount/count` takes
+/// one page, and `/livecount/counts` up to `MAX_BATCH_COUNTS`.
+fn livecount_count(
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let single = warp::path!("livecount" / "count").map(|| false);
+    let batch = warp::path!("livecount" / "counts").map(|| true);
+    single
+        .or(batch)
+        .unify()
+        .and(warp::get())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(move |batch, query, accept, if_none_match| {
+            livecount_count_map(
+                batch,
+                query,
+                accept,
+                if_none_match,
+                reg.clone(),
+                config.clone(),
+            )
+        })
+}
+
+/// Get the badge style from a badge request.
+fn badge_style_from_query(
+    querymap: &HashMap<String, String>,
+) -> Result<badge::Style, WsRequestError> {
+    let mut style = badge::Style::default();
+    if let Some(label) = querymap.get("label") {
+        if label.chars().count() > badge::MAX_LABEL_LEN {
+            return Err(WsRequestError::InvalidBadgeOption("label".to_owned()));
+        }
+        style.label = label.to_owned();
+    }
+    for (name, color) in [
+        ("color", &mut style.color),
+        ("label_color", &mut style.label_color),
+    ] {
+        if let Some(value) = querymap.get(name) {
+            *color = badge::parse_color(value)
+                .ok_or_else(|| WsRequestError::InvalidBadgeOption(name.to_owned()))?;
+        }
+    }
+    if let Some(corners) = querymap.get("style") {
+        style.corners = corners
+            .parse()
+            .map_err(|_| WsRequestError::InvalidBadgeOption("style".to_owned()))?;
+    }
+    if let Some(rounding) = querymap.get("round") {
+        style.rounding = rounding
+            .parse()
+            .map_err(|_| WsRequestError::InvalidBadgeOption("round".to_owned()))?;
+    }
+    Ok(style)
+}
+
+async fn livecount_badge_map(
+    querymap: HashMap<String, String>,
+    if_none_match: Option<String>,
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> Result<Response, warp::Rejection> {
+    let url = match livecount_url_from_query(&querymap) {
+        Ok(url) => url,
+        Err(err) => {
+            debug!("Rejecting badge request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let key = match page_key(&config, &url) {
+        Ok(key) => key,
+        Err(err) => {
+            debug!("Rejecting badge request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let style = match badge_style_from_query(&querymap) {
+        Ok(style) => style,
+        Err(err) => {
+            debug!("Rejecting badge request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let Some(counts) = reg.counts(&[key]).await else {
+        return Ok(
+            warp::reply::with_status("registry not ready", StatusCode::SERVICE_UNAVAILABLE)
+                .into_response(),
+        );
+    };
+    Ok(etag_reply(
+        badge::render(counts[0].count, &style),
+        "image/svg+xml",
+        BADGE_CACHE_CONTROL,
+        if_none_match.as_deref(),
+    ))
+}
+
+/// Current count of a page as an SVG badge, for places without JavaScript.
+fn livecount_badge(
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "badge.svg")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(move |querymap, if_none_match| {
+            livecount_badge_map(querymap, if_none_match, reg.clone(), config.clone())
+        })
+}
+
+#[derive(serde::Serialize)]
+struct PollReply {
+    #[serde(flatten)]
+    count: Count,
+    lease: String,
+}
+
+async fn livecount_poll_map(
+    remote: Option<std::net::SocketAddr>,
+    heads: HeaderMap,
+    querymap: HashMap<String, String>,
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+    limiter: Limiter,
+) -> Result<Response, warp::Rejection> {
+    let url = match livecount_url_from_query(&querymap) {
+        Ok(url) => url,
+        Err(err) => {
+            warn!("Rejecting poll request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    if let Err(err) = validate_origin(&config.origins, &url, request_origin(&heads).as_deref()) {
+        warn!("Rejecting poll request: {err}");
+        return Ok(request_error_response(&err));
+    }
+    let key = match page_key(&config, &url) {
+        Ok(key) => key,
+        Err(err) => {
+            warn!("Rejecting poll request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let seen = match seen_count_from_query(&querymap) {
+        Ok(seen) => seen,
+        Err(err) => {
+            debug!("Rejecting poll request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let (viewer, new_cookie) = match viewer_from_request(&heads, &querymap, &reg, &config) {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("Rejecting poll request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    if let Err(err) = validate_token(&config, &key, &querymap, &viewer) {
+        warn!("Rejecting poll request: {err}");
+        return Ok(request_error_response(&err));
+    }
+    // Held while the request waits for a change.
+    let _permit = match acquire_permit(&limiter, remote) {
+        Ok(permit) => permit,
+        Err(err) => {
+            debug!("Rejecting poll request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let lease = match querymap.get("lease") {
+        Some(lease) if valid_visitor_id(lease) => lease.to_owned(),
+        _ => new_visitor_id(),
+    };
+    let Some(count) = session::poll(&reg, &key, &lease, viewer, seen).await else {
+        return Ok(
+            warp::reply::with_status("registry not ready", StatusCode::SERVICE_UNAVAILABLE)
+                .into_response(),
+        );
+    };
+    let mut response = warp::reply::json(&PollReply { count, lease }).into_response();
+    if let Some(id) = new_cookie {
+        set_visitor_cookie(&mut response, &id);
+    }
+    Ok(response)
+}
+
+/// Long-poll for count changes, for clients that can use neither websockets
+/// nor SSE.
+fn livecount_poll(
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+    limiter: Limiter,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "poll")
+        .and(warp::get())
+        .and(remote_addr())
+        .and(warp::filters::header::headers_cloned())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |remote, heads, querymap| {
+            livecount_poll_map(
+                remote,
+                heads,
+                querymap,
+                reg.clone(),
+                config.clone(),
+                limiter.clone(),
+            )
+        })
+}
+
+#[derive(serde::Serialize)]
+struct HistoryReply {
+    url: String,
+    interval_secs: u64,
+    samples: Vec<Sample>,
+}
+
+async fn livecount_history_map(
+    querymap: HashMap<String, String>,
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> Result<Response, warp::Rejection> {
+    let url = match livecount_url_from_query(&querymap) {
+        Ok(url) => url,
+        Err(err) => {
+            debug!("Rejecting history request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let key = match page_key(&config, &url) {
+        Ok(key) => key,
+        Err(err) => {
+            debug!("Rejecting history request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let Some(samples) = reg.history(&key).await else {
+        return Ok(warp::reply::with_status(
+            "registry unavailable",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+        .into_response());
+    };
+    Ok(warp::reply::json(&HistoryReply {
+        url: key,
+        interval_secs: reg.config().history_interval.as_secs(),
+        samples,
+    })
+    .into_response())
+}
+
+/// Per-page count history, for drawing graphs.
+fn livecount_history(
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "history")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |querymap| livecount_history_map(querymap, reg.clone(), config.clone()))
+}
+
+#[derive(serde::Serialize)]
+struct PeaksReply {
+    url: String,
+    all_time: Option<Peak>,
+    today: Option<Peak>,
+}
+
+async fn livecount_peaks_map(
+    querymap: HashMap<String, String>,
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> Result<Response, warp::Rejection> {
+    let url = match livecount_url_from_query(&querymap) {
+        Ok(url) => url,
+        Err(err) => {
+            debug!("Rejecting peaks request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let key = match page_key(&config, &url) {
+        Ok(key) => key,
+        Err(err) => {
+            debug!("Rejecting peaks request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let peaks = reg.peaks(&key).await;
+    Ok(warp::reply::json(&PeaksReply {
+        url: key,
+        all_time: peaks.map(|p| p.all_time),
+        today: peaks.and_then(|p| p.today),
+    })
+    .into_response())
+}
+
+/// Peak concurrent viewers per page, all-time and for the current UTC day.
+fn livecount_peaks(
+    reg: Arc<Registry>,
+    config: Arc<Config>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "peaks")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |querymap| livecount_peaks_map(querymap, reg.clone(), config.clone()))
+}
+
+async fn livecount_top_map(
+    querymap: HashMap<String, String>,
+    reg: Arc<Registry>,
+) -> Result<Response, warp::Rejection> {
+    let (n, host) = match top_params_from_query(&querymap) {
+        Ok(params) => params,
+        Err(err) => {
+            debug!("Rejecting top request: {err}");
+            return Ok(request_error_response(&err));
+        }
+    };
+    let Some(pages) = reg.top(n, host).await else {
+        return Ok(warp::reply::with_status(
+            "registry unavailable",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+        .into_response());
+    };
+    Ok(warp::reply::json(&TopReply { pages: &pages }).into_response())
+}
+
+/// Pages with the most current viewers.
+fn livecount_top(
+    reg: Arc<Registry>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "top")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |querymap| livecount_top_map(querymap, reg.clone()))
+}
+
+fn livecount_top_ws_map(
+    ws: warp::ws::Ws,
+    remote: Option<std::net::SocketAddr>,
+    querymap: HashMap<String, String>,
+    top: session::TopFeed,
+    keepalive: Keepalive,
+    limiter: &Limiter,
+) -> Response {
+    let (n, host) = match top_params_from_query(&querymap) {
+        Ok((n, host)) => (n, host.map(str::to_owned)),
+        Err(err) => {
+            warn!("Rejecting top websocket request: {err}");
+            return request_error_response(&err);
+        }
+    };
+    let permit = match acquire_permit(limiter, remote) {
+        Ok(permit) => permit,
+        Err(err) => {
+            debug!("Rejecting top websocket request: {err}");
+            return request_error_response(&err);
+        }
+    };
+    ws.max_message_size(MAX_WS_MESSAGE_SIZE)
+        .max_frame_size(MAX_WS_MESSAGE_SIZE)
+        .on_upgrade(move |websocket| async move {
+            let _permit = permit;
+            let socket = websocket
+                .with(|f: Frame| {
+                    futures_util::future::ready(Ok::<_, warp::Error>(Message::from(f)))
+                })
+                .map(|m| m.map(Frame::from));
+            session::run_top(socket, &top, n, host.as_deref(), &keepalive).await;
+        })
+        .into_response()
+}
+
+/// Pages with the most current viewers, pushed whenever they change.
+fn livecount_top_ws(
+    top: session::TopFeed,
+    keepalive: Keepalive,
+    limiter: Limiter,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "top" / "ws")
+        .and(warp::ws())
+        .and(remote_addr())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |ws, remote, querymap| {
+            livecount_top_ws_map(
+                ws,
+                remote,
+                querymap,
+                top.clone(),
+                keepalive.clone(),
+                &limiter,
+            )
+        })
+}
+
+fn livecount_multi_ws_map(
+    ws: warp::ws::Ws,
+    remote: Option<std::net::SocketAddr>,
+    origin: Option<String>,
+    reg: Arc<Registry>,
+    keepalive: Keepalive,
+    config: Arc<Config>,
+    limiter: &Limiter,
+) -> Response {
+    // Subscribed pages are checked against the origin one by one, but a
+    // missing or broken origin can be rejected up front.
+    let origin = match origin.ok_or(WsRequestError::MissingOrigin).and_then(|o| {
+        url::Url::parse(&o)
+            .map(|_| o)
+            .map_err(WsRequestError::InvalidOrigin)
+    }) {
+        Ok(origin) => origin,
+        Err(err) => {
+            warn!("Rejecting multi websocket request: {err}");
+            return request_error_response(&err);
+        }
+    };
+    let permit = match acquire_permit(limiter, remote) {
+        Ok(permit) => permit,
+        Err(err) => {
+            debug!("Rejecting multi websocket request: {err}");
+            return request_error_response(&err);
+        }
+    };
+    ws.max_message_size(MAX_WS_MESSAGE_SIZE)
+        .max_frame_size(MAX_WS_MESSAGE_SIZE)
+        .on_upgrade(move |websocket| async move {
+            let _permit = permit;
+            let socket = websocket
+                .with(|f: Frame| {
+                    futures_util::future::ready(Ok::<_, warp::Error>(Message::from(f)))
+                })
+                .map(|m| m.map(Frame::from));
+            let key_for = |location: &str| {
+                let url = livecount_url(location).map_err(|e| e.to_string())?;
+                validate_origin(&config.origins, &url, Some(&origin)).map_err(|e| e.to_string())?;
+                page_key(&config, &url).map_err(|e| e.to_string())
+            };
+            session::run_multi(socket, key_for, &reg, &keepalive).await;
+        })
+        .into_response()
+}
+
+/// Counts of many pages over one websocket, without being counted.
+fn livecount_multi_ws(
+    reg: Arc<Registry>,
+    keepalive: Keepalive,
+    config: Arc<Config>,
+    limiter: Limiter,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "multi" / "ws")
+        .and(warp::ws())
+        .and(remote_addr())
+        .and(warp::header::optional::<String>("origin"))
+        .map(move |ws, remote, origin| {
+            livecount_multi_ws_map(
+                ws,
+                remote,
+                origin,
+                reg.clone(),
+                keepalive.clone(),
+                config.clone(),
+                &limiter,
+            )
+        })
+}
+
+/// Readiness check, failing while a registry shard is restarting.
+fn livecount_ready(
+    reg: Arc<Registry>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("livecount" / "ready")
+        .and(warp::get())
+        .map(move || {
+            if reg.is_ready() {
+                warp::reply::with_status("ready", StatusCode::OK)
+            } else {
+                warp::reply::with_status("registry not ready", StatusCode::SERVICE_UNAVAILABLE)
+            }
+        })
+}
+
+/// The widget script, at `/livecount/widget.js` for the latest version, and
+/// at e.g. `/livecount/widget-v1.js` for a pinned version.
+fn livecount_widget() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+{
+    let pinned = format!("widget-v{WIDGET_VERSION}.js");
+    warp::path("livecount")
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(move |name: String, if_none_match: Option<String>| {
+            let found = name == "widget.js" || name == pinned;
+            async move {
+                if !found {
+                    return Err(warp::reject::not_found());
+                }
+                Ok(etag_reply(
+                    WIDGET_JS.to_owned(),
+                    "text/javascript; charset=utf-8",
+                    WIDGET_CACHE_CONTROL,
+                    if_none_match.as_deref(),
+                ))
+            }
+        })
+}
+
+fn livecount_index() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+{
+    debug!("livecount_index()");
+    warp::path!("livecount" / "health")
+        .and(warp::get())
+        .map(|| {
+            warp::reply::html(
+                r##"
+<html>
+<head>
+</head>
+<body>
+Live counter: <span id="counter"></span>
+<script src="/livecount/widget.js" data-target="#counter"></script>
+</body>
+</html>
+"##,
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{
+        livecount, livecount_url_from_query, livecount_with_config, new_visitor_id, prefers_text,
+        request_origin, valid_visitor_id, validate_observer_secret, validate_origin, Config,
+        WsRequestError,
+    };
+    use crate::canonical;
+    use crate::handoff;
+    use crate::limits;
+    use crate::origin;
+    use crate::registry::{self, CountMode, Registry};
+    use crate::token;
+
+    #[test]
+    fn rejects_missing_or_invalid_livecount_url() {
+        let querymap = HashMap::new();
+        assert!(matches!(
+            livecount_url_from_query(&querymap),
+            Err(WsRequestError::MissingLocation)
+        ));
+
+        let querymap = HashMap::from([("l".to_string(), "not a url".to_string())]);
+        assert!(matches!(
+            livecount_url_from_query(&querymap),
+            Err(WsRequestError::InvalidLocation(_))
+        ));
+    }
+
+    #[test]
+    fn strips_query_from_livecount_key() {
+        let querymap = HashMap::from([(
+            "l".to_string(),
+            "https://example.test/page?cachebust=1".to_string(),
+        )]);
+
+        let url = livecount_url_from_query(&querymap).unwrap();
+        let key = Config::default().canonical.key(&url);
+        assert_eq!(key, "https://example.test/page");
+    }
+
+    #[test]
+    fn validates_origin_against_livecount_url() {
+        let url = url::Url::parse("https://example.test:443/page").unwrap();
+        let policy = origin::Policy::default();
+
+        assert!(validate_origin(&policy, &url, Some("https://example.test")).is_ok());
+        assert!(matches!(
+            validate_origin(&policy, &url, None),
+            Err(WsRequestError::MissingOrigin)
+        ));
         assert!(matches!(
-            validate_origin(&url, Some("https://evil.test")),
+            validate_origin(&policy, &url, Some("https://evil.test")),
             Err(WsRequestError::OriginMismatch { .. })
         ));
         assert!(matches!(
-            validate_origin(&url, Some("not a url")),
+            validate_origin(&policy, &url, Some("not a url")),
             Err(WsRequestError::InvalidOrigin(_))
         ));
+
+        let policy =
+            origin::Policy::new(vec!["https://*.partner.test=example.test".parse().unwrap()]);
+        assert!(validate_origin(&policy, &url, Some("https://www.partner.test")).is_ok());
+        assert!(validate_origin(&policy, &url, Some("https://example.test")).is_ok());
+        assert!(matches!(
+            validate_origin(&policy, &url, Some("https://evil.test")),
+            Err(WsRequestError::OriginMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn gets_origin_from_referer() {
+        let mut heads = warp::http::HeaderMap::new();
+        assert_eq!(request_origin(&heads), None);
+        heads.insert(
+            "referer",
+            "https://example.test:8443/page?x=1".parse().unwrap(),
+        );
+        assert_eq!(
+            request_origin(&heads).as_deref(),
+            Some("https://example.test:8443")
+        );
+        heads.insert("origin", "https://other.test".parse().unwrap());
+        assert_eq!(
+            request_origin(&heads).as_deref(),
+            Some("https://other.test")
+        );
+    }
+
+    /// Measure memory per idle websocket connection.
+    ///
+    /// This is the growth in resident memory divided by the number of
+    /// connections. Both ends of the connections are in this process, but
+    /// the client end is only a `TcpStream`.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_idle_connection_memory() {
+        const CONNECTIONS: usize = 5_000;
+        let reg = Arc::new(Registry::new());
+        let (addr, server) = warp::serve(livecount(reg)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Warm up allocator and runtime.
+        let (warmup, _) = connect(addr, "https://example.test/warmup").await;
+        drop(warmup);
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        let before = rss_bytes();
+        let mut conns = Vec::with_capacity(CONNECTIONS);
+        for n in 0..CONNECTIONS {
+            let (conn, _) = connect(addr, &format!("https://example.test/{}", n % 100)).await;
+            conns.push(conn);
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let after = rss_bytes();
+        println!(
+            "{CONNECTIONS} idle websockets: {} bytes per connection",
+            (after - before) / CONNECTIONS
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_count_on_connect() {
+        let reg = Arc::new(Registry::new());
+        let (addr, server) = warp::serve(livecount(reg)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let (mut c1, mut buf) = connect(addr, "https://example.test/page").await;
+        assert_eq!(read_text(&mut c1, &mut buf).await, "1");
+        let (mut c2, mut buf2) = connect(addr, "https://example.test/page").await;
+        assert_eq!(read_text(&mut c2, &mut buf2).await, "2");
+        assert_eq!(read_text(&mut c1, &mut buf).await, "2");
+    }
+
+    #[tokio::test]
+    async fn negotiates_json_protocol() {
+        let reg = Arc::new(Registry::new());
+        let (addr, server) = warp::serve(livecount(reg)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let page = "https://example.test/page";
+
+        let (mut c1, heads, mut buf) =
+            connect_with(addr, page, "Sec-WebSocket-Protocol: livecount.v2\r\n").await;
+        assert!(
+            heads.contains("sec-websocket-protocol: livecount.v2"),
+            "{heads}"
+        );
+        assert_eq!(
+            read_text(&mut c1, &mut buf).await,
+            r#"{"type":"count","count":1}"#
+        );
+
+        let (mut c2, heads, mut buf2) = connect_with(addr, page, "").await;
+        assert!(!heads.contains("sec-websocket-protocol"), "{heads}");
+        assert_eq!(read_text(&mut c2, &mut buf2).await, "2");
+    }
+
+    #[tokio::test]
+    async fn streams_counts_as_sse() {
+        let reg = Arc::new(Registry::new());
+        let (addr, server) =
+            warp::serve(livecount(reg.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /livecount/sse?l=https://example.test/page HTTP/1.1\r\n\
+                  Host: example.test\r\n\
+                  Referer: https://example.test/page\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        // The body is chunked, so look for the event anywhere.
+        let event = b"event:count\ndata:1\n\n";
+        while !buf.windows(event.len()).any(|w| w == event) {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert_ne!(n, 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with("HTTP/1.1 200"), "{text}");
+        assert!(text.contains("content-type: text/event-stream"), "{text}");
+        assert_eq!(
+            reg.local_counts()
+                .await
+                .unwrap()
+                .get("https://example.test/page"),
+            Some(&1)
+        );
+
+        let res = warp::test::request()
+            .path("/livecount/sse?l=https://example.test/page")
+            .header("origin", "https://evil.test")
+            .reply(&livecount(reg))
+            .await;
+        assert_eq!(res.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn counts_canonical_pages() {
+        let reg = Arc::new(Registry::new());
+        let config = Config {
+            canonical: canonical::Rules {
+                merge_schemes: true,
+                strip_trailing_slash: true,
+                index_files: vec!["index.html".to_string()],
+                query_params: vec!["page".to_string()],
+                host_aliases: HashMap::from([("www.a.test".to_string(), "a.test".to_string())]),
+            },
+            ..Config::default()
+        };
+        let filter = livecount_with_config(reg.clone(), config);
+        let _h1 = reg.register("https://a.test/page").await.unwrap();
+        let _h2 = reg.register("https://a.test/page?page=2").await.unwrap();
+
+        for (page, want) in [
+            (
+                "http://www.a.test/page/index.html%23comments",
+                "https://a.test/page",
+            ),
+            (
+                "https://a.test/page/?utm_source=feed",
+                "https://a.test/page",
+            ),
+            ("https://a.test/page?page=2", "https://a.test/page?page=2"),
+        ] {
+            let res = warp::test::request()
+                .path(&format!("/livecount/peaks?l={page}"))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 200);
+            let peaks: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(peaks["url"], want, "{page}");
+            assert_eq!(peaks["all_time"]["count"], 1, "{page}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_counts() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+        let _h = reg.register("https://a.test/page").await.unwrap();
+
+        let res = warp::test::request()
+            .path("/livecount/count?l=https://a.test/page?x=1")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"{"url":"https://a.test/page","count":1}"#);
+        let etag = res.headers()["etag"].to_str().unwrap().to_owned();
+
+        let res = warp::test::request()
+            .path("/livecount/count?l=https://a.test/page")
+            .header("if-none-match", &etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 304);
+        assert!(res.body().is_empty());
+
+        let res = warp::test::request()
+            .path("/livecount/count?l=https://a.test/page")
+            .header("accept", "text/plain")
+            .header("if-none-match", &etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "1");
+
+        let res = warp::test::request()
+            .path("/livecount/counts?l=https://a.test/page&l=https://b.test/&format=text")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.body(), "1 https://a.test/page\n0 https://b.test/\n");
+
+        let res = warp::test::request()
+            .path("/livecount/counts?l=https://b.test/")
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            res.body(),
+            r#"{"counts":[{"url":"https://b.test/","count":0}]}"#
+        );
+
+        for path in [
+            "/livecount/count",
+            "/livecount/count?l=https://a.test/&l=https://b.test/",
+        ] {
+            let res = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(res.status(), 400, "{path}");
+        }
+        // Nothing was counted.
+        assert_eq!(reg.local_counts().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn negotiates_count_format() {
+        for (accept, want) in [
+            ("text/plain", true),
+            ("text/html, text/plain", true),
+            ("TEXT/PLAIN; charset=utf-8", true),
+            ("text/*", true),
+            ("text/plain;q=0.5, application/json", false),
+            ("application/json;q=0.5, text/plain;q=0.8", true),
+            ("text/*;q=0.9, text/plain;q=0.1, */*;q=0.5", false),
+            ("text/plain;q=0", false),
+            ("*/*", false),
+            ("text/html", false),
+            ("", false),
+            ("garbage", false),
+        ] {
+            assert_eq!(prefers_text(accept), want, "{accept}");
+        }
+    }
+
+    #[tokio::test]
+    async fn varies_counts_by_accept() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+        let path = "/livecount/count?l=https://a.test/page";
+        for (accept, body) in [
+            (
+                "application/json",
+                r#"{"url":"https://a.test/page","count":0}"#,
+            ),
+            ("text/html, text/plain", "0"),
+        ] {
+            let res = warp::test::request()
+                .path(path)
+                .header("accept", accept)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.body(), body, "{accept}");
+            let vary: Vec<_> = res
+                .headers()
+                .get_all("vary")
+                .iter()
+                .map(|v| v.to_str().unwrap())
+                .collect();
+            assert!(vary.contains(&"accept"), "{vary:?}");
+            assert!(vary.contains(&"origin"), "{vary:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_badges() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+        let _h = reg.register("https://a.test/page").await.unwrap();
+
+        let res = warp::test::request()
+            .path("/livecount/badge.svg?l=https://a.test/page&label=reading&color=blue&style=flat-square")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "image/svg+xml");
+        assert_eq!(
+            res.headers()["cache-control"],
+            "max-age=10, must-revalidate"
+        );
+        let svg = std::str::from_utf8(res.body()).unwrap();
+        assert!(svg.contains(">reading</text>"), "{svg}");
+        assert!(svg.contains(">1</text>"), "{svg}");
+        assert!(svg.contains(r#"fill="blue""#), "{svg}");
+
+        let res = warp::test::request()
+            .path("/livecount/badge.svg?l=https://a.test/page&round=compact")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let long_label = format!("label={}", "x".repeat(41));
+        for query in [
+            "color=%22",
+            "style=round",
+            "round=up",
+            "label_color=",
+            &long_label,
+        ] {
+            let res = warp::test::request()
+                .path(&format!(
+                    "/livecount/badge.svg?l=https://a.test/page&{query}"
+                ))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 400, "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_widget() {
+        let filter = livecount(Arc::new(Registry::new()));
+        for path in ["/livecount/widget.js", "/livecount/widget-v1.js"] {
+            let res = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(res.status(), 200, "{path}");
+            assert_eq!(
+                res.headers()["content-type"],
+                "text/javascript; charset=utf-8"
+            );
+            let js = std::str::from_utf8(res.body()).unwrap();
+            assert!(js.contains("var VERSION = 1;"), "{js}");
+            assert!(!js.contains("innerHTML"));
+        }
+        let res = warp::test::request()
+            .path("/livecount/widget-v0.js")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn long_polls_counts() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+        let page = "https://example.test/page";
+
+        let res = warp::test::request()
+            .path(&format!("/livecount/poll?l={page}&lease=abc"))
+            .header("origin", "https://example.test")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"{"count":1,"version":1,"lease":"abc"}"#);
+
+        // A changed count is returned right away, and the lease still counts.
+        let _h = reg.register(page).await.unwrap();
+        let res = warp::test::request()
+            .path(&format!(
+                "/livecount/poll?l={page}&lease=abc&count=1&version=1"
+            ))
+            .header("origin", "https://example.test")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.body(), r#"{"count":2,"version":2,"lease":"abc"}"#);
+
+        let res = warp::test::request()
+            .path(&format!("/livecount/poll?l={page}&count=x&version=1"))
+            .header("origin", "https://example.test")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn counts_visitors_by_cookie() {
+        let reg = Arc::new(Registry::with_config(registry::Config {
+            count_mode: CountMode::Visitors,
+            ..registry::Config::default()
+        }));
+        let (addr, server) = warp::serve(livecount(reg)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let page = "https://example.test/page";
+
+        let (mut c1, heads, mut buf) = connect_with(addr, page, "").await;
+        assert_eq!(read_text(&mut c1, &mut buf).await, "1");
+        let cookie = heads
+            .lines()
+            .find_map(|l| l.strip_prefix("set-cookie: "))
+            .expect("no visitor cookie set");
+        let (id, _) = cookie.split_once(';').unwrap();
+
+        let (mut c2, heads, mut buf2) =
+            connect_with(addr, page, &format!("Cookie: other=1; {id}\r\n")).await;
+        assert!(!heads.contains("set-cookie"), "{heads}");
+        assert_eq!(read_text(&mut c2, &mut buf2).await, "1");
+
+        let (mut c3, _, mut buf3) = connect_with(addr, &format!("{page}&c=token"), "").await;
+        assert_eq!(read_text(&mut c3, &mut buf3).await, "2");
+        assert_eq!(read_text(&mut c1, &mut buf).await, "2");
+    }
+
+    #[tokio::test]
+    async fn allows_cors_by_policy() {
+        let config = Config {
+            origins: origin::Policy::new(vec!["https://partner.test=example.test"
+                .parse()
+                .unwrap()]),
+            ..Config::default()
+        };
+        let filter = livecount_with_config(Arc::new(Registry::new()), config);
+        for (origin, path, want) in [
+            (
+                "https://partner.test",
+                "/livecount/count?l=https://example.test/page",
+                true,
+            ),
+            (
+                "https://example.test",
+                "/livecount/count?l=https://example.test/page",
+                true,
+            ),
+            (
+                "https://evil.test",
+                "/livecount/count?l=https://example.test/page",
+                false,
+            ),
+            (
+                "https://partner.test",
+                "/livecount/counts?l=https://example.test/a&l=https://other.test/b",
+                false,
+            ),
+            ("https://partner.test", "/livecount/top", true),
+            ("https://evil.test", "/livecount/top", false),
+        ] {
+            let res = warp::test::request()
+                .path(path)
+                .header("origin", origin)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 200, "{origin} {path}");
+            assert!(res.headers().get_all("vary").iter().any(|v| v == "origin"));
+            let allowed = res.headers().get("access-control-allow-origin");
+            assert_eq!(allowed.is_some(), want, "{origin} {path}");
+            if want {
+                assert_eq!(allowed.unwrap(), origin);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_pages() {
+        let reg = Arc::new(Registry::new());
+        let config = Config {
+            pages: canonical::Allowlist {
+                prefixes: vec!["example.test/blog/".parse().unwrap()],
+                max_key_len: 40,
+            },
+            ..Config::default()
+        };
+        let filter = livecount_with_config(reg.clone(), config);
+        let rejections = |reason| registry::PAGE_REJECTIONS.with_label_values(&[reason]).get();
+        let not_allowed = rejections("not_allowed");
+        let too_long = rejections("too_long");
+
+        for (page, status) in [
+            ("https://example.test/blog/a", 101),
+            ("https://example.test/random", 403),
+            ("https://anything.test/blog/a", 403),
+            ("https://example.test/blog/aaaaaaaaaaaaaaaaaaa", 400),
+        ] {
+            let origin = url::Url::parse(page)
+                .unwrap()
+                .origin()
+                .ascii_serialization();
+            let res = ws_request(&format!("/livecount/ws?l={page}"))
+                .header("origin", origin)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), status, "{page}");
+        }
+        assert!(rejections("not_allowed") >= not_allowed + 2);
+        assert!(rejections("too_long") > too_long);
+
+        let res = warp::test::request()
+            .path("/livecount/count?l=https://example.test/random")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(reg.top(10, None).await.unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn requires_page_tokens() {
+        let key = token::Key::new("s3cret");
+        let config = Config {
+            observer_secret: Some("observer".to_string()),
+            canonical: canonical::Rules {
+                strip_trailing_slash: true,
+                ..canonical::Rules::default()
+            },
+            token_key: Some(key.clone()),
+            ..Config::default()
+        };
+        let filter = livecount_with_config(Arc::new(Registry::new()), config);
+        let page = "https://example.test/page";
+        let now = token::now();
+        let valid = key.sign(page, now + 60);
+        let expired = key.sign(page, now - 60);
+        let other = key.sign("https://example.test/other", now + 60);
+
+        for (query, want) in [
+            (format!("l={page}/&t={valid}"), Ok(())),
+            (format!("l={page}&observer=observer"), Ok(())),
+            (format!("l={page}"), Err(WsRequestError::MissingToken)),
+            (
+                format!("l={page}&t={other}"),
+                Err(WsRequestError::InvalidToken),
+            ),
+            (
+                format!("l={page}&t=garbage"),
+                Err(WsRequestError::InvalidToken),
+            ),
+            (
+                format!("l={page}&t={expired}"),
+                Err(WsRequestError::ExpiredToken),
+            ),
+        ] {
+            let res = ws_request(&format!("/livecount/ws?{query}"))
+                .reply(&filter)
+                .await;
+            match want {
+                Ok(()) => assert_eq!(res.status(), 101, "{query}"),
+                Err(err) => {
+                    assert_eq!(res.status(), err.status(), "{query}");
+                    assert_eq!(res.body(), err.client_message(), "{query}");
+                }
+            }
+        }
+
+        let res = warp::test::request()
+            .path(&format!("/livecount/poll?l={page}&t={expired}"))
+            .header("origin", "https://example.test")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_upgrades() {
+        let config = Config {
+            limits: limits::Limits {
+                rate: Some(0.001),
+                burst: 1,
+                ..limits::Limits::default()
+            },
+            ..Config::default()
+        };
+        let filter = livecount_with_config(Arc::new(Registry::new()), config);
+        let rejections = || {
+            registry::CONNECTION_REJECTIONS
+                .with_label_values(&["rate"])
+                .get()
+        };
+        let before = rejections();
+        let request = || ws_request("/livecount/ws?l=https://example.test/page");
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        let res = request()
+            .remote_addr(addr("192.0.2.1:1000"))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 101);
+        let res = request()
+            .remote_addr(addr("192.0.2.1:1001"))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(res.body(), "connecting too often");
+
+        // Handed-off connections are limited by their address too.
+        let res = request()
+            .extension(handoff::RemoteAddr(addr("192.0.2.1:1002")))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 429);
+        let res = request()
+            .extension(handoff::RemoteAddr(addr("192.0.2.2:1000")))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 101);
+        assert!(rejections() >= before + 2);
+    }
+
+    #[tokio::test]
+    async fn limits_all_connections_together() {
+        let config = Config {
+            limits: limits::Limits {
+                max_total: Some(2),
+                ..limits::Limits::default()
+            },
+            ..Config::default()
+        };
+        let filter = livecount_with_config(Arc::new(Registry::new()), config);
+        let (addr, server) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let rejections = || {
+            registry::CONNECTION_REJECTIONS
+                .with_label_values(&["total"])
+                .get()
+        };
+        let before = rejections();
+
+        let (mut c1, mut c1_buf) = connect(addr, "https://example.test/page").await;
+        assert_eq!(read_text(&mut c1, &mut c1_buf).await, "1");
+        let (mut multi, _, mut buf) = upgrade(addr, "/livecount/multi/ws", "").await;
+        write_text(&mut multi, "sub https://example.test/page").await;
+        assert_eq!(
+            read_text(&mut multi, &mut buf).await,
+            "1 https://example.test/page"
+        );
+
+        for path in [
+            "/livecount/multi/ws",
+            "/livecount/top/ws",
+            "/livecount/ws?l=https://example.test/page",
+        ] {
+            let res = ws_request(path).reply(&filter).await;
+            assert_eq!(res.status(), 503, "{path}");
+            assert_eq!(res.body(), "too many connections", "{path}");
+        }
+        for path in [
+            "/livecount/sse?l=https://example.test/page",
+            "/livecount/poll?l=https://example.test/page",
+        ] {
+            let res = warp::test::request()
+                .path(path)
+                .header("origin", "https://example.test")
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 503, "{path}");
+        }
+        assert!(rejections() >= before + 5);
+    }
+
+    #[tokio::test]
+    async fn observers_are_not_counted() {
+        let reg = Arc::new(Registry::new());
+        let config = Config {
+            observer_secret: Some("s3cret".to_string()),
+            ..Config::default()
+        };
+        let filter = livecount_with_config(reg, config);
+        let page = "https://example.test/page";
+
+        for query in ["observer=wrong", "observer="] {
+            let res = ws_request(&format!("/livecount/ws?l={page}&{query}"))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 403);
+        }
+
+        let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (mut obs, _, mut obs_buf) =
+            connect_with(addr, &format!("{page}&observer=s3cret"), "").await;
+        assert_eq!(read_text(&mut obs, &mut obs_buf).await, "0");
+        let (mut c1, mut buf) = connect(addr, page).await;
+        assert_eq!(read_text(&mut c1, &mut buf).await, "1");
+        assert_eq!(read_text(&mut obs, &mut obs_buf).await, "1");
+    }
+
+    #[test]
+    fn compares_observer_secrets() {
+        assert!(validate_observer_secret(Some("abc"), "abc").is_ok());
+        assert!(validate_observer_secret(Some("abc"), "abd").is_err());
+        assert!(validate_observer_secret(Some("abc"), "abcd").is_err());
+        assert!(validate_observer_secret(None, "").is_err());
+    }
+
+    #[test]
+    fn validates_visitor_ids() {
+        assert!(valid_visitor_id("abc-DEF_123"));
+        assert!(!valid_visitor_id(""));
+        assert!(!valid_visitor_id("a b"));
+        assert!(!valid_visitor_id(&"a".repeat(65)));
+        assert_ne!(new_visitor_id(), new_visitor_id());
+    }
+
+    #[tokio::test]
+    async fn serves_history() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+
+        let res = warp::test::request()
+            .path("/livecount/history?l=https://example.test/page")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body(),
+            r#"{"url":"https://example.test/page","interval_secs":60,"samples":[]}"#
+        );
+
+        let res = warp::test::request()
+            .path("/livecount/history")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn serves_peaks() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+
+        let res = warp::test::request()
+            .path("/livecount/peaks?l=https://example.test/page")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body(),
+            r#"{"url":"https://example.test/page","all_time":null,"today":null}"#
+        );
+
+        let _h = reg.register("https://example.test/page").await.unwrap();
+        let res = warp::test::request()
+            .path("/livecount/peaks?l=https://example.test/page")
+            .reply(&filter)
+            .await;
+        let body = std::str::from_utf8(res.body()).unwrap();
+        assert!(
+            body.starts_with(r#"{"url":"https://example.test/page","all_time":{"count":1,"#),
+            "{body}"
+        );
+        assert!(body.contains(r#""today":{"count":1,"#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn serves_top_pages() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+        let _h1 = reg.register("https://a.test/page").await.unwrap();
+        let _h2 = reg.register("https://a.test/page").await.unwrap();
+        let _h3 = reg.register("https://b.test/page").await.unwrap();
+
+        let res = warp::test::request()
+            .path("/livecount/top?n=1")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body(),
+            r#"{"pages":[{"url":"https://a.test/page","count":2}]}"#
+        );
+
+        let res = warp::test::request()
+            .path("/livecount/top?host=b.test")
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            res.body(),
+            r#"{"pages":[{"url":"https://b.test/page","count":1}]}"#
+        );
+
+        for n in ["0", "101", "x"] {
+            let res = warp::test::request()
+                .path(&format!("/livecount/top?n={n}"))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 400);
+        }
+    }
+
+    #[tokio::test]
+    async fn multiplexes_page_counts() {
+        let reg = Arc::new(Registry::new());
+        let (addr, server) =
+            warp::serve(livecount(reg.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let (mut multi, _, mut buf) = upgrade(addr, "/livecount/multi/ws", "").await;
+        write_text(&mut multi, "sub https://example.test/a?x=1").await;
+        assert_eq!(
+            read_text(&mut multi, &mut buf).await,
+            "0 https://example.test/a?x=1"
+        );
+        write_text(&mut multi, "sub https://evil.test/a").await;
+        assert_eq!(
+            read_text(&mut multi, &mut buf).await,
+            "error https://evil.test/a"
+        );
+        let (mut c1, mut c1_buf) = connect(addr, "https://example.test/a").await;
+        assert_eq!(read_text(&mut c1, &mut c1_buf).await, "1");
+        assert_eq!(
+            read_text(&mut multi, &mut buf).await,
+            "1 https://example.test/a?x=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_readiness() {
+        let reg = Arc::new(Registry::new());
+        let filter = livecount(reg.clone());
+        // Make sure the shard has started.
+        reg.local_counts().await.unwrap();
+        let res = warp::test::request()
+            .path("/livecount/ready")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+    }
+
+    /// A websocket upgrade request for `path`, from the example.test origin.
+    fn ws_request(path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .path(path)
+            .header("origin", "https://example.test")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    /// Read one short unmasked text frame from the server.
+    async fn read_text(stream: &mut TcpStream, buf: &mut Vec<u8>) -> String {
+        while buf.len() < 2 || buf.len() < 2 + usize::from(buf[1]) {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert_ne!(n, 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(buf[0], 0x81, "not a final text frame");
+        let len = usize::from(buf[1]);
+        assert!(len < 126);
+        let text = String::from_utf8(buf[2..2 + len].to_vec()).unwrap();
+        buf.drain(..2 + len);
+        text
+    }
+
+    /// Write a short masked text frame, as a client.
+    async fn write_text(stream: &mut TcpStream, text: &str) {
+        assert!(text.len() < 126);
+        let mut frame = vec![0x81, 0x80 | text.len() as u8, 0, 0, 0, 0];
+        // With a zero mask key, the payload is sent as is.
+        frame.extend_from_slice(text.as_bytes());
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    /// Open a websocket, returning the stream and any data read after the
+    /// upgrade response.
+    async fn connect(addr: SocketAddr, page: &str) -> (TcpStream, Vec<u8>) {
+        let (stream, _, buf) = connect_with(addr, page, "").await;
+        (stream, buf)
+    }
+
+    /// Connect with extra request headers, each ending in CRLF. Returns the
+    /// response headers too.
+    async fn connect_with(
+        addr: SocketAddr,
+        page: &str,
+        headers: &str,
+    ) -> (TcpStream, String, Vec<u8>) {
+        upgrade(addr, &format!("/livecount/ws?l={page}"), headers).await
+    }
+
+    /// Open a websocket on any path.
+    async fn upgrade(addr: SocketAddr, path: &str, headers: &str) -> (TcpStream, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {path} HTTP/1.1\r\n\
+             Host: example.test\r\n\
+             Origin: https://example.test\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             {headers}\r\n"
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = Vec::new();
+        let end = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert_ne!(n, 0, "connection closed during upgrade");
+            buf.extend_from_slice(&chunk[..n]);
+        };
+        assert!(buf.starts_with(b"HTTP/1.1 101"));
+        let rest = buf.split_off(end);
+        (stream, String::from_utf8(buf).unwrap(), rest)
+    }
+
+    fn rss_bytes() -> usize {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status
+            .lines()
+            .find(|l| l.starts_with("VmRSS:"))
+            .expect("no VmRSS in /proc/self/status");
+        let kb: usize = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        kb * 1024
     }
 }
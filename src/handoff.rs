@@ -4,6 +4,7 @@ use std::fs;
 use std::io;
 use std::io::Cursor;
 use std::mem;
+use std::net::SocketAddr;
 use std::net::TcpStream as StdTcpStream;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::FileTypeExt;
@@ -15,7 +16,7 @@ use std::ptr;
 use std::task::{Context, Poll};
 
 use futures_util::stream;
-use log::warn;
+use log::{debug, warn};
 use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
 use tokio::net::{TcpStream, UnixDatagram};
 
@@ -45,6 +46,13 @@ impl<T> PrefixedIo<T> {
     }
 }
 
+impl PrefixedTcpStream {
+    /// Address of the client.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
 impl<T: AsyncRead + Unpin> AsyncRead for PrefixedIo<T> {
     fn poll_read(
         mut self: Pin<&mut Self>,
@@ -109,6 +117,14 @@ pub fn configure_socket_path(
     Ok(())
 }
 
+/// Remote address of a handed-off connection, set as a request extension by
+/// [`serve`]. warp only knows the remote address of connections it accepted
+/// itself.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Handed-off connections. Unlike with [`serve`], warp doesn't know their
+/// remote addresses.
 pub fn incoming(
     socket: UnixDatagram,
 ) -> impl futures_util::Stream<Item = io::Result<PrefixedTcpStream>> + Send {
@@ -118,6 +134,49 @@ pub fn incoming(
     })
 }
 
+/// Serve handed-off connections with a service, e.g. from
+/// `warp::service(filter)`. Requests get the remote address of their
+/// connection as a [`RemoteAddr`] extension.
+pub async fn serve<S>(socket: UnixDatagram, service: S)
+where
+    S: warp::hyper::service::Service<
+            warp::hyper::Request<warp::hyper::Body>,
+            Response = warp::hyper::Response<warp::hyper::Body>,
+            Error = std::convert::Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    loop {
+        let Ok(stream) = next_handoff(&socket).await else {
+            continue;
+        };
+        let remote = match stream.peer_addr() {
+            Ok(addr) => Some(RemoteAddr(addr)),
+            Err(err) => {
+                debug!("failed to get address of handed-off connection: {err}");
+                None
+            }
+        };
+        let mut service = service.clone();
+        let service = warp::hyper::service::service_fn(move |mut req| {
+            if let Some(remote) = remote {
+                req.extensions_mut().insert(remote);
+            }
+            service.call(req)
+        });
+        tokio::spawn(async move {
+            let conn = warp::hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades();
+            if let Err(err) = conn.await {
+                debug!("handed-off connection failed: {err}");
+            }
+        });
+    }
+}
+
 async fn next_handoff(socket: &UnixDatagram) -> io::Result<PrefixedTcpStream> {
     loop {
         match receive_handoff(socket).await {
@@ -0,0 +1,258 @@
+//! Shared keepalive scheduler for idle connections.
+//!
+//! Instead of every connection re-arming its own timers on activity, all
+//! connections are kept in a timer wheel driven by one task. Activity only
+//! updates an atomic timestamp in the connection. When a connection's slot
+//! comes up, the scheduler checks the timestamps and either tells the
+//! connection to send a ping or to expire, or puts it back in the slot for its
+//! new deadline.
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use log::trace;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
+
+/// Resolution of the timer wheel.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Number of slots in the timer wheel. Deadlines further away than this many
+/// ticks are checked once per lap.
+const SLOTS: usize = 1024;
+
+const EVENT_NONE: u8 = 0;
+const EVENT_PING: u8 = 1;
+const EVENT_EXPIRE: u8 = 2;
+
+/// What the scheduler wants a connection to do.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Connection has been idle long enough that it should send a ping.
+    Ping,
+
+    /// Connection has been idle for its max lifetime, and should close.
+    Expire,
+}
+
+/// Keepalive state of one connection.
+pub struct Conn {
+    /// Start of the scheduler, that the timestamps are relative to.
+    epoch: Instant,
+
+    /// Last activity that keeps the connection alive, in ms since `epoch`.
+    alive: AtomicU64,
+
+    /// Last activity that postpones sending a ping, in ms since `epoch`.
+    ping: AtomicU64,
+
+    event: AtomicU8,
+    notify: Notify,
+}
+
+impl Conn {
+    /// Record activity that keeps the connection alive.
+    pub fn renew(&self) {
+        let now = self.now();
+        self.alive.store(now, Ordering::Relaxed);
+        self.ping.store(now, Ordering::Relaxed);
+    }
+
+    /// Postpone the next ping.
+    pub fn renew_ping(&self) {
+        self.ping.store(self.now(), Ordering::Relaxed);
+    }
+
+    /// Wait for the scheduler to ask for something.
+    pub async fn next(&self) -> Event {
+        loop {
+            match self.event.swap(EVENT_NONE, Ordering::AcqRel) {
+                EVENT_PING => return Event::Ping,
+                EVENT_EXPIRE => return Event::Expire,
+                _ => self.notify.notified().await,
+            }
+        }
+    }
+
+    fn now(&self) -> u64 {
+        u64::try_from((Instant::now() - self.epoch).as_millis()).unwrap_or(u64::MAX)
+    }
+
+    fn at(&self, ms: &AtomicU64) -> Instant {
+        self.epoch + Duration::from_millis(ms.load(Ordering::Relaxed))
+    }
+
+    fn signal(&self, event: u8) {
+        self.event.fetch_max(event, Ordering::AcqRel);
+        self.notify.notify_one();
+    }
+}
+
+struct Wheel {
+    slots: Vec<Vec<Weak<Conn>>>,
+
+    /// Number of ticks processed since the scheduler started.
+    tick: u64,
+}
+
+impl Wheel {
+    fn insert(&mut self, epoch: Instant, due: Instant, conn: Weak<Conn>) {
+        // Round up, so that a connection is never checked before it's due.
+        let ticks = (due - epoch).as_nanos().div_ceil(TICK.as_nanos());
+        let ticks = u64::try_from(ticks).unwrap_or(u64::MAX).max(self.tick + 1);
+        let slot = usize::try_from(ticks % SLOTS as u64).unwrap();
+        self.slots[slot].push(conn);
+    }
+}
+
+/// Handle to the keepalive scheduler. Cheap to clone.
+#[derive(Clone)]
+pub struct Keepalive {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    epoch: Instant,
+    life: Duration,
+    ping: Duration,
+    wheel: Mutex<Wheel>,
+}
+
+impl Keepalive {
+    /// Start a scheduler expiring connections idle for `life`, and asking
+    /// connections idle for `ping` to send a ping.
+    pub fn new(life: Duration, ping: Duration) -> Self {
+        let inner = Arc::new(Inner {
+            epoch: Instant::now(),
+            life,
+            ping,
+            wheel: Mutex::new(Wheel {
+                slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+                tick: 0,
+            }),
+        });
+        tokio::spawn(Self::run(Arc::downgrade(&inner)));
+        Keepalive { inner }
+    }
+
+    /// Add a connection to the scheduler.
+    ///
+    /// The connection is removed from the scheduler when it's dropped.
+    pub fn add(&self) -> Arc<Conn> {
+        let inner = &self.inner;
+        let conn = Arc::new(Conn {
+            epoch: inner.epoch,
+            alive: AtomicU64::new(0),
+            ping: AtomicU64::new(0),
+            event: AtomicU8::new(EVENT_NONE),
+            notify: Notify::new(),
+        });
+        conn.renew();
+        let due = Instant::now() + inner.ping.min(inner.life);
+        inner
+            .wheel
+            .lock()
+            .unwrap()
+            .insert(inner.epoch, due, Arc::downgrade(&conn));
+        conn
+    }
+
+    async fn run(inner: Weak<Inner>) {
+        let mut interval = tokio::time::interval(TICK);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            inner.process(Instant::now());
+        }
+    }
+}
+
+impl Inner {
+    /// Process all slots up to `now`.
+    fn process(&self, now: Instant) {
+        let now_tick = u64::try_from((now - self.epoch).as_nanos() / TICK.as_nanos()).unwrap();
+        let mut wheel = self.wheel.lock().unwrap();
+        while wheel.tick < now_tick {
+            wheel.tick += 1;
+            let slot = usize::try_from(wheel.tick % SLOTS as u64).unwrap();
+            let conns = std::mem::take(&mut wheel.slots[slot]);
+            trace!("Keepalive slot {slot} has {} connections", conns.len());
+            for weak in conns {
+                let Some(conn) = weak.upgrade() else {
+                    continue;
+                };
+                let deadline = conn.at(&conn.alive) + self.life;
+                if deadline <= now {
+                    conn.signal(EVENT_EXPIRE);
+                    continue;
+                }
+                let mut ping_deadline = conn.at(&conn.ping) + self.ping;
+                if ping_deadline <= now {
+                    conn.signal(EVENT_PING);
+                    // Don't ask for another ping until the connection has been
+                    // idle for another ping interval.
+                    conn.ping.store(conn.now(), Ordering::Relaxed);
+                    ping_deadline = now + self.ping;
+                }
+                wheel.insert(self.epoch, deadline.min(ping_deadline), weak);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Duration;
+
+    use super::{Event, Keepalive};
+
+    #[tokio::test(start_paused = true)]
+    async fn pings_and_expires_idle_connections() {
+        let ka = Keepalive::new(Duration::from_secs(60), Duration::from_secs(50));
+        let conn = ka.add();
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(conn.next().await, Event::Ping);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(50), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_secs(52), "{elapsed:?}");
+
+        assert_eq!(conn.next().await, Event::Expire);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(60), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_secs(62), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn activity_postpones_expiry() {
+        let ka = Keepalive::new(Duration::from_secs(60), Duration::from_secs(50));
+        let conn = ka.add();
+        let start = tokio::time::Instant::now();
+
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        conn.renew();
+        assert_eq!(conn.next().await, Event::Ping);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(90), "{elapsed:?}");
+
+        conn.renew();
+        assert_eq!(conn.next().await, Event::Ping);
+        assert_eq!(conn.next().await, Event::Expire);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(150), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_secs(152), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handles_deadlines_longer_than_the_wheel() {
+        let ka = Keepalive::new(Duration::from_secs(3000), Duration::from_secs(2500));
+        let conn = ka.add();
+        let start = tokio::time::Instant::now();
+        assert_eq!(conn.next().await, Event::Ping);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2500), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_secs(2502), "{elapsed:?}");
+    }
+}
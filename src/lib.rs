@@ -0,0 +1,66 @@
+//! Live count of how many browsers are currently open to a page.
+//!
+//! This is the engine behind the `livecount` binary, for embedding in other
+//! servers:
+//!
+//! * [`Registry`] keeps track of viewers per page, and hands out a
+//!   [`Handle`] per viewer that receives count updates.
+//! * [`session::run`] drives one viewer's websocket, over any websocket
+//!   implementation.
+//! * [`handoff`] accepts connections handed off over a Unix socket, e.g. by
+//!   sni-router from tarweb.
+//! * [`limits`] limits websocket connections per address and in total.
+//! * [`badge`] renders counts as SVG badges.
+//! * [`canonical`] turns page URLs into the keys they are counted under.
+//! * [`origin`] decides which origins may count which pages.
+//! * [`token`] signs and verifies page tokens.
+//! * [`filters`] has ready made warp filters for all of the above.
+//!
+//! For example, with a tokio-tungstenite websocket `ws`:
+//!
+//! ```ignore
+//! use futures_util::{SinkExt, StreamExt};
+//! use livecount::session::{self, Frame};
+//! use tungstenite::Message;
+//!
+//! // Once at startup.
+//! let reg = livecount::Registry::new();
+//! let keepalive = session::keepalive();
+//!
+//! // Per websocket, after validating the page URL.
+//! let socket = ws
+//!     .with(|f: Frame| async move {
+//!         Ok::<_, tungstenite::Error>(match f {
+//!             Frame::Text(t) => Message::text(t),
+//!             Frame::Binary(b) => Message::binary(b),
+//!             Frame::Ping(b) => Message::Ping(b.into()),
+//!             Frame::Pong(b) => Message::Pong(b.into()),
+//!             Frame::Close => Message::Close(None),
+//!         })
+//!     })
+//!     .map(|m| {
+//!         m.map(|m| match m {
+//!             Message::Text(t) => Frame::Text(t.to_string()),
+//!             Message::Ping(b) => Frame::Ping(b.into()),
+//!             Message::Pong(b) => Frame::Pong(b.into()),
+//!             Message::Close(_) => Frame::Close,
+//!             m => Frame::Binary(m.into_data().into()),
+//!         })
+//!     });
+//! let page = "https://example.com/page";
+//! let viewer = livecount::Viewer::Anonymous;
+//! session::run(socket, page, viewer, session::Protocol::V1, &reg, &keepalive).await;
+//! ```
+pub mod badge;
+pub mod canonical;
+pub mod cluster;
+pub mod filters;
+pub mod handoff;
+pub mod keepalive;
+pub mod limits;
+pub mod origin;
+pub mod registry;
+pub mod session;
+pub mod token;
+
+pub use registry::{Count, Handle, Lease, Registry, Viewer};
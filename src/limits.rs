@@ -0,0 +1,244 @@
+//! Connection limits, so that one client can't hold thousands of websockets,
+//! inflating counts or running the server out of file descriptors.
+//!
+//! Clients are told apart by IP address. There are limits on concurrent
+//! connections per address and in total, and a token bucket per address
+//! limiting how often it may connect.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::time::Instant;
+
+/// Don't bother sweeping idle addresses until there are this many.
+const MIN_SWEEP: usize = 1024;
+
+/// Connection limits. `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Max concurrent connections.
+    pub max_total: Option<usize>,
+
+    /// Max concurrent connections per address.
+    pub max_per_addr: Option<usize>,
+
+    /// New connections per second per address, on average.
+    pub rate: Option<f64>,
+
+    /// New connections per address in a burst, on top of the rate. At least
+    /// one.
+    pub burst: u32,
+}
+
+/// Why a connection was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Too many connections in total.
+    Total,
+
+    /// Too many connections from the address.
+    PerAddr,
+
+    /// The address connects too often.
+    Rate,
+}
+
+impl Rejection {
+    /// Reason for metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::Total => "total",
+            Rejection::PerAddr => "per_addr",
+            Rejection::Rate => "rate",
+        }
+    }
+}
+
+struct Addr {
+    conns: usize,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Addr {
+    /// Refill the token bucket up to `now`.
+    fn refill(&mut self, limits: &Limits, now: Instant) {
+        if let Some(rate) = limits.rate {
+            let elapsed = now.duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(burst(limits));
+        }
+        self.updated = now;
+    }
+}
+
+fn burst(limits: &Limits) -> f64 {
+    f64::from(limits.burst.max(1))
+}
+
+struct State {
+    total: usize,
+    addrs: HashMap<IpAddr, Addr>,
+    sweep_at: usize,
+}
+
+struct Inner {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+/// Hands out [`Permit`]s for connections within the limits. Cheap to clone.
+#[derive(Clone)]
+pub struct Limiter {
+    inner: Arc<Inner>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Limiter {
+            inner: Arc::new(Inner {
+                limits,
+                state: Mutex::new(State {
+                    total: 0,
+                    addrs: HashMap::new(),
+                    sweep_at: MIN_SWEEP,
+                }),
+            }),
+        }
+    }
+
+    /// Get a permit for a new connection from `addr`, if it's within the
+    /// limits. Connections from unknown addresses are only held to the
+    /// total limit.
+    ///
+    /// The connection counts until the permit is dropped.
+    pub fn acquire(&self, addr: Option<IpAddr>) -> Result<Permit, Rejection> {
+        let limits = &self.inner.limits;
+        let now = Instant::now();
+        let mut state = self.inner.state.lock().unwrap();
+        if limits.max_total.is_some_and(|max| state.total >= max) {
+            return Err(Rejection::Total);
+        }
+        if let Some(addr) = addr {
+            if state.addrs.len() >= state.sweep_at {
+                // Forget addresses without connections, once they could
+                // connect at the full burst again anyway.
+                state.addrs.retain(|_, a| {
+                    a.refill(limits, now);
+                    a.conns > 0 || a.tokens < burst(limits)
+                });
+                state.sweep_at = (state.addrs.len() * 2).max(MIN_SWEEP);
+            }
+            let a = state.addrs.entry(addr).or_insert_with(|| Addr {
+                conns: 0,
+                tokens: burst(limits),
+                updated: now,
+            });
+            a.refill(limits, now);
+            if limits.max_per_addr.is_some_and(|max| a.conns >= max) {
+                return Err(Rejection::PerAddr);
+            }
+            if limits.rate.is_some() {
+                if a.tokens < 1.0 {
+                    return Err(Rejection::Rate);
+                }
+                a.tokens -= 1.0;
+            }
+            a.conns += 1;
+        }
+        state.total += 1;
+        Ok(Permit {
+            inner: self.inner.clone(),
+            addr,
+        })
+    }
+
+    /// Number of connections holding permits.
+    pub fn total(&self) -> usize {
+        self.inner.state.lock().unwrap().total
+    }
+}
+
+/// A connection within the limits.
+pub struct Permit {
+    inner: Arc<Inner>,
+    addr: Option<IpAddr>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.total -= 1;
+        if let Some(a) = self.addr.and_then(|addr| state.addrs.get_mut(&addr)) {
+            a.conns -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use tokio::time::Duration;
+
+    use super::{Limiter, Limits, Rejection};
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn limits_concurrent_connections() {
+        let limiter = Limiter::new(Limits {
+            max_total: Some(3),
+            max_per_addr: Some(2),
+            ..Limits::default()
+        });
+        let a1 = limiter.acquire(ip("192.0.2.1")).unwrap();
+        let _a2 = limiter.acquire(ip("192.0.2.1")).unwrap();
+        assert_eq!(
+            limiter.acquire(ip("192.0.2.1")).err(),
+            Some(Rejection::PerAddr)
+        );
+        let _b1 = limiter.acquire(ip("192.0.2.2")).unwrap();
+        assert_eq!(limiter.acquire(None).err(), Some(Rejection::Total));
+        assert_eq!(limiter.total(), 3);
+
+        drop(a1);
+        assert_eq!(limiter.total(), 2);
+        let _a3 = limiter.acquire(ip("192.0.2.1")).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_connection_rate() {
+        let limiter = Limiter::new(Limits {
+            rate: Some(2.0),
+            burst: 3,
+            ..Limits::default()
+        });
+        for _ in 0..3 {
+            drop(limiter.acquire(ip("2001:db8::1")).unwrap());
+        }
+        assert_eq!(
+            limiter.acquire(ip("2001:db8::1")).err(),
+            Some(Rejection::Rate)
+        );
+        // Other addresses have their own buckets, and unknown ones none.
+        assert!(limiter.acquire(ip("2001:db8::2")).is_ok());
+        assert!(limiter.acquire(None).is_ok());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.acquire(ip("2001:db8::1")).is_ok());
+        assert_eq!(
+            limiter.acquire(ip("2001:db8::1")).err(),
+            Some(Rejection::Rate)
+        );
+
+        // Never more than the burst.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            assert!(limiter.acquire(ip("2001:db8::1")).is_ok());
+        }
+        assert!(limiter.acquire(ip("2001:db8::1")).is_err());
+        assert_eq!(limiter.total(), 0);
+    }
+}
@@ -8,11 +8,7 @@ use log::info;
 use warp::Filter;
 //use prometheus
 
-mod filters;
-mod handoff;
-mod registry;
-
-use registry::Registry;
+use livecount::{canonical, cluster, filters, handoff, limits, origin, registry, token, Registry};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
 pub enum LogLevel {
@@ -61,6 +57,129 @@ struct Opt {
     /// TLS private key for the direct TCP listener.
     #[arg(long)]
     key: Option<PathBuf>,
+
+    /// Number of registry shards. Defaults to the number of CPUs.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    registry_shards: Option<u16>,
+
+    /// How often to sample per-page counts for /livecount/history, in seconds.
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    history_interval_secs: u64,
+
+    /// Number of history samples to keep per page. 0 disables history.
+    #[arg(long, default_value = "60")]
+    history_samples: usize,
+
+    /// Forget all peak viewer counts, not just daily peaks, at UTC midnight.
+    #[arg(long)]
+    reset_peaks_daily: bool,
+
+    /// Max number of pages to keep peak viewer counts of. Past it, the peaks
+    /// of the pages with the lowest all-time peaks are forgotten.
+    #[arg(long, default_value = "100000")]
+    peaks_limit: usize,
+
+    /// Max number of pages with their own label in per-page metrics. Other
+    /// pages are counted under the page label "other".
+    #[arg(long, default_value = "1000")]
+    page_metrics_limit: usize,
+
+    /// Only give pages starting with this URL prefix their own label in
+    /// per-page metrics, e.g. "https://example.com/". Can be repeated.
+    #[arg(long)]
+    page_metrics_prefix: Vec<String>,
+
+    /// What to count as viewers: "handles" counts every open tab, "visitors"
+    /// counts browsers, identified by a cookie or a c= query parameter.
+    #[arg(long, default_value = "handles")]
+    count_mode: registry::CountMode,
+
+    /// File with a secret that lets websocket clients get counts without
+    /// being counted, by passing it as the observer= query parameter.
+    #[arg(long)]
+    observer_secret_file: Option<std::path::PathBuf>,
+
+    /// File with a key shared with the site, for signing page tokens. If set,
+    /// counting requests must have a token for the page as the t= query
+    /// parameter.
+    #[arg(long)]
+    token_key_file: Option<std::path::PathBuf>,
+
+    /// Let another origin count pages, as ORIGIN or ORIGIN=PAGE_HOST, e.g.
+    /// "https://*.cdn.ampproject.org=example.com". Hosts may start with "*."
+    /// for any subdomain. Without a page host, the origin may count any page.
+    /// Pages' own origins are always allowed. Can be repeated.
+    #[arg(long)]
+    allow_origin: Vec<origin::Rule>,
+
+    /// Count http:// pages as their https:// counterparts.
+    #[arg(long)]
+    canonical_merge_schemes: bool,
+
+    /// Count pages with a trailing slash as the page without it.
+    #[arg(long)]
+    canonical_strip_trailing_slash: bool,
+
+    /// Count pages ending with this file name, e.g. "index.html", as their
+    /// directory. Can be repeated.
+    #[arg(long)]
+    canonical_index_file: Vec<String>,
+
+    /// Keep this query parameter in page URLs, e.g. "page", instead of
+    /// removing it like all others. Can be repeated.
+    #[arg(long)]
+    canonical_query_param: Vec<String>,
+
+    /// Count pages on a host as pages on another, as ALIAS=HOST, e.g.
+    /// "www.example.com=example.com". Can be repeated.
+    #[arg(long, value_parser = canonical::parse_host_alias)]
+    canonical_host_alias: Vec<(String, String)>,
+
+    /// Only count pages on this host and under this path prefix, e.g.
+    /// "example.com" or "example.com/blog/". Can be repeated. By default any
+    /// page is counted.
+    #[arg(long)]
+    allow_page: Vec<canonical::Prefix>,
+
+    /// Max length of page URLs, after canonicalization.
+    #[arg(long, default_value_t = canonical::DEFAULT_MAX_KEY_LEN)]
+    max_page_url_len: usize,
+
+    /// Max number of websockets, SSE streams and long polls. By default
+    /// unlimited.
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Max number of connections from one IP address. By default unlimited.
+    #[arg(long)]
+    max_connections_per_addr: Option<usize>,
+
+    /// New connections per second allowed from one IP address, on average. By
+    /// default unlimited.
+    #[arg(long, value_parser = parse_rate)]
+    connect_rate: Option<f64>,
+
+    /// New connections allowed from one IP address at once, on top of
+    /// --connect-rate.
+    #[arg(long, default_value = "10", requires = "connect_rate")]
+    connect_burst: u32,
+
+    /// Listen for cluster gossip from peers on this UDP address.
+    #[arg(long)]
+    cluster_listen: Option<std::net::SocketAddr>,
+
+    /// Cluster peer UDP address to exchange counts with. Can be repeated.
+    #[arg(long, requires = "cluster_listen")]
+    cluster_peer: Vec<std::net::SocketAddr>,
+
+    /// How often to send counts to cluster peers, in milliseconds.
+    #[arg(
+        long,
+        default_value = "5000",
+        requires = "cluster_listen",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    cluster_interval_ms: u64,
 }
 
 fn parse_octal_mode(value: &str) -> std::result::Result<u32, String> {
@@ -72,6 +191,24 @@ fn parse_octal_mode(value: &str) -> std::result::Result<u32, String> {
     Ok(mode)
 }
 
+fn parse_rate(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("invalid rate {value:?}, must be a positive number")),
+    }
+}
+
+/// Read a secret from a file, ignoring surrounding whitespace.
+fn read_secret(path: &std::path::Path, what: &str) -> Result<String> {
+    let secret = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {what} {}", path.display()))?;
+    let secret = secret.trim().to_string();
+    if secret.is_empty() {
+        bail!("{what} file {} is empty", path.display());
+    }
+    Ok(secret)
+}
+
 async fn metrics_handler() -> Result<impl warp::Reply, warp::Rejection> {
     use prometheus::Encoder;
     let encoder = prometheus::TextEncoder::new();
@@ -125,8 +262,71 @@ async fn main() -> Result<()> {
         .expect("Failed to initialize logging");
     info!("Running");
 
-    let reg = Arc::new(Registry::new());
-    let api = filters::livecount(reg.clone())
+    let shards = opt.registry_shards.map(usize::from).unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    info!("Using {shards} registry shards");
+    let reg = Arc::new(Registry::with_config(registry::Config {
+        shards,
+        history_interval: std::time::Duration::from_secs(opt.history_interval_secs),
+        history_samples: opt.history_samples,
+        reset_peaks_daily: opt.reset_peaks_daily,
+        peaks_limit: opt.peaks_limit,
+        page_metrics_limit: opt.page_metrics_limit,
+        page_metrics_prefixes: opt.page_metrics_prefix,
+        count_mode: opt.count_mode,
+    }));
+    if let Some(listen) = opt.cluster_listen {
+        let socket = cluster::bind(listen)
+            .await
+            .with_context(|| format!("failed to bind cluster socket {listen}"))?;
+        info!(
+            "Gossiping counts on {listen} with {} peers",
+            opt.cluster_peer.len()
+        );
+        tokio::spawn(cluster::run(
+            reg.clone(),
+            socket,
+            opt.cluster_peer.clone(),
+            std::time::Duration::from_millis(opt.cluster_interval_ms),
+        ));
+    }
+    let observer_secret = opt
+        .observer_secret_file
+        .as_deref()
+        .map(|path| read_secret(path, "observer secret"))
+        .transpose()?;
+    let token_key = opt
+        .token_key_file
+        .as_deref()
+        .map(|path| read_secret(path, "token key"))
+        .transpose()?
+        .map(token::Key::new);
+    let config = filters::Config {
+        observer_secret,
+        origins: origin::Policy::new(opt.allow_origin),
+        canonical: canonical::Rules {
+            merge_schemes: opt.canonical_merge_schemes,
+            strip_trailing_slash: opt.canonical_strip_trailing_slash,
+            index_files: opt.canonical_index_file,
+            query_params: opt.canonical_query_param,
+            host_aliases: opt.canonical_host_alias.into_iter().collect(),
+        },
+        pages: canonical::Allowlist {
+            prefixes: opt.allow_page,
+            max_key_len: opt.max_page_url_len,
+        },
+        token_key,
+        limits: limits::Limits {
+            max_total: opt.max_connections,
+            max_per_addr: opt.max_connections_per_addr,
+            rate: opt.connect_rate,
+            burst: opt.connect_burst,
+        },
+    };
+    let api = filters::livecount_with_config(reg.clone(), config)
         .or(warp::path!("livecount" / "metrics").and_then(metrics_handler));
     let routes = api.with(warp::log("livecount"));
 
@@ -173,7 +373,7 @@ async fn main() -> Result<()> {
         (Some((path, listener)), Some((listen, cert, key))) => {
             info!("Listening for socket handoffs on {}", path.display());
             info!("Listening directly on TCP/TLS at {listen}");
-            let unix_server = warp::serve(routes.clone()).run_incoming(handoff::incoming(listener));
+            let unix_server = handoff::serve(listener, warp::service(routes.clone()));
             let tcp_server = warp::serve(routes)
                 .tls()
                 .cert_path(cert)
@@ -183,9 +383,7 @@ async fn main() -> Result<()> {
         }
         (Some((path, listener)), None) => {
             info!("Listening for socket handoffs on {}", path.display());
-            warp::serve(routes)
-                .run_incoming(handoff::incoming(listener))
-                .await;
+            handoff::serve(listener, warp::service(routes)).await;
         }
         (None, Some((listen, cert, key))) => {
             info!("Listening directly on TCP/TLS at {listen}");
@@ -207,7 +405,9 @@ async fn main() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_octal_mode;
+    use clap::Parser;
+
+    use super::{parse_octal_mode, parse_rate, Opt};
 
     #[test]
     fn parses_unix_socket_mode_as_octal() {
@@ -221,4 +421,28 @@ mod tests {
         assert!(parse_octal_mode("668").is_err());
         assert!(parse_octal_mode("10000").is_err());
     }
+
+    #[test]
+    fn parses_connect_rates() {
+        assert_eq!(parse_rate("0.5").unwrap(), 0.5);
+        assert_eq!(parse_rate("10").unwrap(), 10.0);
+        for bad in ["0", "-1", "inf", "NaN", "fast"] {
+            assert!(parse_rate(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn rejects_zero_cluster_interval() {
+        let args = |interval: &str| {
+            Opt::try_parse_from([
+                "livecount",
+                "--cluster-listen",
+                "127.0.0.1:7000",
+                "--cluster-interval-ms",
+                interval,
+            ])
+        };
+        assert_eq!(args("100").unwrap().cluster_interval_ms, 100);
+        assert!(args("0").is_err());
+    }
 }
@@ -0,0 +1,235 @@
+//! Which origins may count pages, and read their counts.
+//!
+//! A page's own origin may always count it. Other origins need a [`Rule`],
+//! e.g. for AMP caches, `www.` and apex domains serving the same articles,
+//! or partners syndicating them.
+use url::Url;
+
+/// An exact host, or any subdomain of a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Exact(String),
+
+    /// `*.example.com`, matching `www.example.com` and `a.b.example.com`,
+    /// but not `example.com`.
+    Subdomains(String),
+}
+
+impl HostPattern {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (wildcard, host) = match s.strip_prefix("*.") {
+            Some(host) => (true, host),
+            None => (false, s),
+        };
+        let host = match url::Host::parse(host) {
+            Ok(url::Host::Domain(host)) => host,
+            Ok(host) if !wildcard => host.to_string(),
+            _ => return Err(format!("invalid host {s:?}")),
+        };
+        Ok(if wildcard {
+            HostPattern::Subdomains(host)
+        } else {
+            HostPattern::Exact(host)
+        })
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(h) => host == h,
+            HostPattern::Subdomains(h) => host
+                .strip_suffix(h.as_str())
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        }
+    }
+}
+
+/// An origin allowed to count pages other than its own.
+///
+/// Written as `ORIGIN` or `ORIGIN=PAGE_HOST`, where the host of the origin
+/// may start with `*.` for any subdomain. Without a page host the origin may
+/// count pages on any host, and with one only pages on that host, which may
+/// also start with `*.`. For example
+/// `https://*.cdn.ampproject.org=example.com` lets AMP caches count pages on
+/// `example.com`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    scheme: String,
+    host: HostPattern,
+    port: Option<u16>,
+    pages: Option<HostPattern>,
+}
+
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (origin, pages) = match s.split_once('=') {
+            Some((origin, pages)) => (origin, Some(HostPattern::parse(pages)?)),
+            None => (s, None),
+        };
+        let invalid = || format!("invalid origin {origin:?}");
+        let (scheme, rest) = origin.split_once("://").ok_or_else(invalid)?;
+        // The wildcard is not part of a valid URL, so parse without it.
+        let (wildcard, rest) = match rest.strip_prefix("*.") {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let url = Url::parse(&format!("{scheme}://{rest}")).map_err(|_| invalid())?;
+        if url.path() != "/"
+            || url.query().is_some()
+            || url.fragment().is_some()
+            || !url.username().is_empty()
+            || rest.ends_with('/')
+        {
+            return Err(invalid());
+        }
+        let host = url.host_str().ok_or_else(invalid)?;
+        let host = if wildcard {
+            HostPattern::parse(&format!("*.{host}"))?
+        } else {
+            HostPattern::Exact(host.to_owned())
+        };
+        Ok(Rule {
+            scheme: url.scheme().to_owned(),
+            host,
+            port: url.port_or_known_default(),
+            pages,
+        })
+    }
+}
+
+impl Rule {
+    fn matches_origin(&self, origin: &Url) -> bool {
+        origin.scheme() == self.scheme
+            && origin.port_or_known_default() == self.port
+            && origin.host_str().is_some_and(|h| self.host.matches(h))
+    }
+
+    fn matches_page(&self, page: &Url) -> bool {
+        match &self.pages {
+            None => true,
+            Some(pages) => page.host_str().is_some_and(|h| pages.matches(h)),
+        }
+    }
+}
+
+/// Origins allowed to count pages, besides the pages' own origins.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Policy { rules }
+    }
+
+    /// Whether `origin` may count `page`.
+    pub fn allows(&self, origin: &Url, page: &Url) -> bool {
+        same_origin(origin, page)
+            || self
+                .rules
+                .iter()
+                .any(|r| r.matches_origin(origin) && r.matches_page(page))
+    }
+
+    /// Whether some rule lets `origin` count other origins' pages at all.
+    pub fn allows_any(&self, origin: &Url) -> bool {
+        self.rules.iter().any(|r| r.matches_origin(origin))
+    }
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str() == b.host_str()
+        && a.port_or_known_default() == b.port_or_known_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, Rule};
+    use url::Url;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        for ok in [
+            "https://example.com",
+            "https://*.example.com",
+            "http://localhost:8080",
+            "https://*.cdn.ampproject.org=example.com",
+            "https://partner.test=*.example.com",
+        ] {
+            assert!(ok.parse::<Rule>().is_ok(), "{ok}");
+        }
+        for bad in [
+            "",
+            "example.com",
+            "https://",
+            "https://example.com/",
+            "https://example.com/page",
+            "https://example.com?a=b",
+            "https://user@example.com",
+            "https://*.example.com=",
+            "https://example.com=*.",
+            "https://example.com=a b",
+        ] {
+            assert!(bad.parse::<Rule>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn allows_origins() {
+        let policy = Policy::new(
+            [
+                "https://example.net",
+                "https://*.cdn.ampproject.org=example.com",
+                "https://www.example.org=*.example.org",
+            ]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect(),
+        );
+        let page = url("https://example.com/page");
+        for (origin, page, want) in [
+            // Same origin.
+            ("https://example.com", &page, true),
+            ("http://example.com", &page, false),
+            ("https://example.com:8443", &page, false),
+            // Any page.
+            ("https://example.net", &page, true),
+            ("https://example.net:443", &page, true),
+            ("https://www.example.net", &page, false),
+            // Subdomains, for one host.
+            ("https://example-com.cdn.ampproject.org", &page, true),
+            ("https://a.b.cdn.ampproject.org", &page, true),
+            ("https://cdn.ampproject.org", &page, false),
+            ("https://evilcdn.ampproject.org", &page, false),
+            (
+                "https://x.cdn.ampproject.org",
+                &url("https://www.example.com/page"),
+                false,
+            ),
+            // Page subdomains.
+            (
+                "https://www.example.org",
+                &url("https://news.example.org/page"),
+                true,
+            ),
+            (
+                "https://www.example.org",
+                &url("https://example.org/page"),
+                false,
+            ),
+            ("https://evil.test", &page, false),
+        ] {
+            assert_eq!(policy.allows(&url(origin), page), want, "{origin} {page}");
+        }
+        assert!(policy.allows_any(&url("https://x.cdn.ampproject.org")));
+        assert!(!policy.allows_any(&url("https://example.com")));
+        assert!(!Policy::default().allows_any(&url("https://example.com")));
+    }
+}
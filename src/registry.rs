@@ -1,19 +1,105 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::LazyLock;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock};
 
-use futures::{pin_mut, select};
-use futures_timer::Delay;
-use futures_util::FutureExt;
-use log::{debug, error, trace, warn};
+use log::{debug, error, warn};
 use prometheus::{
     Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry as PromReg,
 };
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::SendError;
-use tokio::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::time::{Duration, Instant};
 
 const CHANNEL_SIZE: usize = 10_000;
 
+/// How long to wait for a shard to answer a registration.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the supervisor checks that its shard is making progress.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A shard making no progress for this long is restarted. Idle shards make
+/// progress every `EXPIRY_INTERVAL`.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check for cluster peer counts and leases that have expired.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a key's count counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CountMode {
+    /// Every handle, i.e. every open tab, is a viewer.
+    #[default]
+    Handles,
+
+    /// Handles with the same visitor ID are one viewer. Handles without a
+    /// visitor ID are one viewer each.
+    Visitors,
+}
+
+impl std::str::FromStr for CountMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "handles" => Ok(CountMode::Handles),
+            "visitors" => Ok(CountMode::Visitors),
+            _ => Err(format!(
+                "invalid count mode {s:?}, must be \"handles\" or \"visitors\""
+            )),
+        }
+    }
+}
+
+/// Registry settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of actors to split keys across.
+    pub shards: usize,
+
+    /// How often to sample counts into the per-key history.
+    pub history_interval: Duration,
+
+    /// Number of history samples to keep per key. Zero disables history.
+    pub history_samples: usize,
+
+    /// Forget all peaks, including all-time peaks, at UTC midnight.
+    pub reset_peaks_daily: bool,
+
+    /// Max number of pages to keep peaks of. Past it, the pages with the
+    /// lowest all-time peaks are forgotten.
+    pub peaks_limit: usize,
+
+    /// Max number of pages with their own per-page metric labels. Other
+    /// pages are counted in the `other` page label.
+    pub page_metrics_limit: usize,
+
+    /// Only pages starting with one of these get per-page metric labels. If
+    /// empty, any page can.
+    pub page_metrics_prefixes: Vec<String>,
+
+    /// Whether to count handles or distinct visitors.
+    pub count_mode: CountMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            shards: 1,
+            history_interval: Duration::from_secs(60),
+            history_samples: 60,
+            reset_peaks_daily: false,
+            peaks_limit: 100_000,
+            page_metrics_limit: 1000,
+            page_metrics_prefixes: Vec::new(),
+            count_mode: CountMode::Handles,
+        }
+    }
+}
+
 pub static REGISTRY: LazyLock<PromReg> = LazyLock::new(PromReg::new);
 
 pub static PING_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
@@ -36,7 +122,10 @@ pub static TOTAL_ACTIVE: LazyLock<IntGauge> = LazyLock::new(|| {
 
 pub static PAGE_ACTIVE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
     let metric = IntGaugeVec::new(
-        prometheus::Opts::new("page_active", "Active sessions per page"),
+        prometheus::Opts::new(
+            "page_active",
+            "Active sessions per page. Pages without their own label are counted as \"other\".",
+        ),
         &["page"],
     )
     .expect("failed to create page_active metric");
@@ -44,6 +133,39 @@ pub static PAGE_ACTIVE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
     metric
 });
 
+pub static PAGE_PEAK: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    let metric = IntGaugeVec::new(
+        prometheus::Opts::new(
+            "page_peak",
+            "Max concurrent viewers per page with current viewers, all-time or for the current UTC day.",
+        ),
+        &["page", "period"],
+    )
+    .expect("failed to create page_peak metric");
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+});
+
+pub static REGISTRY_RESTARTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let metric = IntCounterVec::new(
+        prometheus::Opts::new(
+            "registry_restarts",
+            "Registry shard restarts, by reason (panic or stall).",
+        ),
+        &["reason"],
+    )
+    .expect("failed to create metric");
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+});
+
+pub static REGISTRY_READY: LazyLock<IntGauge> = LazyLock::new(|| {
+    let metric = IntGauge::new("registry_shards_ready", "Registry shards that are running.")
+        .expect("failed to create metric");
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+});
+
 pub static TIMEOUTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
     let metric = IntCounterVec::new(
         prometheus::Opts::new("timeouts", "Websocket timeout counter"),
@@ -84,8 +206,59 @@ pub static WS_RX_TYPE: LazyLock<IntCounterVec> = LazyLock::new(|| {
     metric
 });
 
+pub static CLUSTER_MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let metric = IntCounterVec::new(
+        prometheus::Opts::new("cluster_messages", "Cluster gossip messages, by direction."),
+        &["direction", "status"],
+    )
+    .expect("failed to create metric");
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+});
+
+pub static CLUSTER_PEERS: LazyLock<IntGauge> = LazyLock::new(|| {
+    let metric = IntGauge::new("cluster_peers", "Cluster peers with unexpired counts.")
+        .expect("failed to create metric");
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+});
+
+pub static CONNECTION_REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let metric = IntCounterVec::new(
+        prometheus::Opts::new(
+            "connection_rejections",
+            "Connections refused for being over the connection limits, by limit.",
+        ),
+        &["reason"],
+    )
+    .expect("failed to create metric");
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+});
+
+pub static PAGE_REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let metric = IntCounterVec::new(
+        prometheus::Opts::new(
+            "page_rejections",
+            "Requests for pages that may not be counted, by reason.",
+        ),
+        &["reason"],
+    )
+    .expect("failed to create metric");
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+});
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use tokio::time::{Duration, Instant};
+
+    use super::{
+        Config, Count, CountMode, Peaks, Request, Viewer, REGISTRY_RESTARTS, SECS_PER_DAY,
+        STALL_TIMEOUT,
+    };
     use crate::Registry;
 
     #[tokio::test]
@@ -104,157 +277,1822 @@ mod tests {
         reg1.stop().await.unwrap();
         reg2.stop().await.unwrap();
     }
+
+    #[tokio::test]
+    async fn coalesces_updates() {
+        let reg = Registry::new();
+        let mut h1 = reg.register("foo").await.unwrap();
+        let h2 = reg.register("foo").await.unwrap();
+        let _h3 = reg.register("foo").await.unwrap();
+        assert_eq!(3, h1.next().await.unwrap());
+
+        h2.close().await;
+        assert_eq!(2, h1.next().await.unwrap());
+        reg.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unregisters_dropped_handles() {
+        let reg = Registry::new();
+        let mut h1 = reg.register("foo").await.unwrap();
+        let h2 = reg.register("foo").await.unwrap();
+        assert_eq!(2, h1.next().await.unwrap());
+        drop(h2);
+        assert_eq!(1, h1.next().await.unwrap());
+        reg.stop().await.unwrap();
+    }
+
+    /// Benchmark the cost of a count update on a page with many viewers.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_popular_page_update() {
+        const VIEWERS: usize = 50_000;
+        const UPDATES: u32 = 10_000;
+        let reg = Registry::new();
+        let mut handles = Vec::with_capacity(VIEWERS);
+        for _ in 0..VIEWERS {
+            handles.push(reg.register("popular").await.unwrap());
+        }
+
+        let start = std::time::Instant::now();
+        for _ in 0..UPDATES {
+            let h = reg.register("popular").await.unwrap();
+            h.close().await;
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{VIEWERS} viewers: {:?} per join+leave ({UPDATES} iterations)",
+            elapsed / UPDATES
+        );
+        drop(handles);
+        reg.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shards_keys() {
+        let reg = Registry::with_config(Config {
+            shards: 4,
+            ..Default::default()
+        });
+        let mut handles = Vec::new();
+        for n in 0..20 {
+            let key = format!("key{n}");
+            let mut h1 = reg.register(&key).await.unwrap();
+            assert_eq!(1, h1.next().await.unwrap());
+            let mut h2 = reg.register(&key).await.unwrap();
+            assert_eq!(2, h1.next().await.unwrap());
+            assert_eq!(2, h2.next().await.unwrap());
+            h2.close().await;
+            assert_eq!(1, h1.next().await.unwrap());
+            handles.push(h1);
+        }
+        let counts = reg.local_counts().await.unwrap();
+        assert_eq!(counts.len(), 20);
+        assert!(counts.values().all(|c| *c == 1));
+        reg.stop().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn samples_history() {
+        let reg = Registry::with_config(Config {
+            history_interval: Duration::from_secs(10),
+            history_samples: 3,
+            ..Default::default()
+        });
+        let h1 = reg.register("foo").await.unwrap();
+        let _h2 = reg.register("foo").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        h1.close().await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let counts = |h: Vec<super::Sample>| h.iter().map(|s| s.count).collect::<Vec<_>>();
+        assert_eq!(counts(reg.history("foo").await.unwrap()), vec![2, 1]);
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert_eq!(counts(reg.history("foo").await.unwrap()), vec![1, 1, 1]);
+        assert!(reg.history("bar").await.unwrap().is_empty());
+        reg.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tracks_peaks() {
+        let reg = Registry::new();
+        assert!(reg.peaks("foo").await.is_none());
+        let h1 = reg.register("foo").await.unwrap();
+        let h2 = reg.register("foo").await.unwrap();
+        h1.close().await;
+        let _h3 = reg.register("foo").await.unwrap();
+        let peaks = reg.peaks("foo").await.unwrap();
+        assert_eq!(peaks.all_time.count, 2);
+        assert_eq!(peaks.today.unwrap().count, 2);
+        h2.close().await;
+        assert_eq!(reg.peaks("foo").await.unwrap().all_time.count, 2);
+        reg.stop().await.unwrap();
+    }
+
+    #[test]
+    fn resets_peaks_on_new_day() {
+        let key: std::sync::Arc<str> = "test-peaks-day".into();
+        let day = 20_000 * SECS_PER_DAY;
+        let mut peaks = Peaks::new(false, 10);
+        peaks.observe(&key, 5, day + 10, false);
+        peaks.observe(&key, 2, day + SECS_PER_DAY + 10, false);
+        let got = peaks.get(&key).unwrap();
+        assert_eq!(got.all_time.count, 5);
+        assert_eq!(got.today.unwrap().count, 2);
+
+        let mut peaks = Peaks::new(true, 10);
+        peaks.observe(&key, 5, day + 10, false);
+        peaks.roll(day + SECS_PER_DAY);
+        assert!(peaks.get(&key).is_none());
+    }
+
+    #[test]
+    fn caps_peaks() {
+        let keys: Vec<std::sync::Arc<str>> = (0..12)
+            .map(|i| format!("test-peaks-cap-{i}").into())
+            .collect();
+        let mut peaks = Peaks::new(false, 10);
+        for (i, key) in keys[..10].iter().enumerate() {
+            peaks.observe(key, 10 - i as u64, 1000 + i as u64, false);
+        }
+        assert_eq!(peaks.keys.len(), 10);
+
+        // The lowest peak is forgotten, and known keys don't evict others.
+        peaks.observe(&keys[10], 1, 2000, false);
+        assert!(peaks.get(&keys[9]).is_none());
+        peaks.observe(&keys[0], 20, 2000, false);
+        assert_eq!(peaks.keys.len(), 10);
+        peaks.observe(&keys[11], 1, 3000, false);
+        assert!(peaks.get(&keys[10]).is_none());
+        assert!(peaks.get(&keys[11]).is_some());
+        assert_eq!(peaks.get(&keys[0]).unwrap().all_time.count, 20);
+        assert_eq!(peaks.keys.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn ranks_top_pages() {
+        let reg = Registry::with_config(Config {
+            shards: 4,
+            ..Config::default()
+        });
+        let mut changes = reg.changes();
+        let mut handles = Vec::new();
+        for (page, viewers) in [
+            ("https://a.test/1", 3),
+            ("https://a.test/2", 1),
+            ("https://b.test/1", 2),
+        ] {
+            for _ in 0..viewers {
+                handles.push(reg.register(page).await.unwrap());
+            }
+        }
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        let top = reg.top(2, None).await.unwrap();
+        let got: Vec<_> = top.iter().map(|p| (p.url.as_str(), p.count)).collect();
+        assert_eq!(got, [("https://a.test/1", 3), ("https://b.test/1", 2)]);
+
+        let top = reg.top(10, Some("a.test")).await.unwrap();
+        let got: Vec<_> = top.iter().map(|p| (p.url.as_str(), p.count)).collect();
+        assert_eq!(got, [("https://a.test/1", 3), ("https://a.test/2", 1)]);
+
+        handles.pop().unwrap().close().await;
+        let top = reg.top(10, Some("b.test")).await.unwrap();
+        assert_eq!(top[0].count, 1);
+        assert!(changes.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn limits_page_metric_labels() {
+        fn labeled(page: &str) -> bool {
+            super::REGISTRY
+                .gather()
+                .iter()
+                .filter(|family| family.name() == "page_active")
+                .flat_map(|family| family.get_metric())
+                .any(|metric| metric.get_label().iter().any(|l| l.value() == page))
+        }
+        let reg = Registry::with_config(Config {
+            page_metrics_limit: 1,
+            page_metrics_prefixes: vec!["https://labeled.test/".to_string()],
+            ..Config::default()
+        });
+        let _h1 = reg.register("https://unlabeled.test/").await.unwrap();
+        let h2 = reg.register("https://labeled.test/a").await.unwrap();
+        let _h3 = reg.register("https://labeled.test/b").await.unwrap();
+        assert!(!labeled("https://unlabeled.test/"));
+        assert!(labeled("https://labeled.test/a"));
+        assert!(!labeled("https://labeled.test/b"));
+
+        h2.close().await;
+        let _h4 = reg.register("https://labeled.test/c").await.unwrap();
+        assert!(!labeled("https://labeled.test/a"));
+        assert!(labeled("https://labeled.test/c"));
+    }
+
+    #[tokio::test]
+    async fn restarts_panicked_shard() {
+        let reg = Registry::new();
+        let restarts = REGISTRY_RESTARTS.with_label_values(&["panic"]);
+        let before = restarts.get();
+        let mut h1 = reg.register("foo").await.unwrap();
+        assert_eq!(h1.next().await, Some(1));
+        assert!(reg.is_ready());
+
+        reg.send("foo", Request::Panic).await;
+        assert_eq!(h1.next().await, Some(1));
+        assert!(restarts.get() > before);
+        assert!(reg.is_ready());
+
+        let mut h2 = reg.register("foo").await.unwrap();
+        assert_eq!(h1.next().await, Some(2));
+        assert_eq!(h2.next().await, Some(2));
+        h2.close().await;
+        assert_eq!(h1.next().await, Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_stalled_shard() {
+        let reg = Registry::new();
+        let restarts = REGISTRY_RESTARTS.with_label_values(&["stall"]);
+        let before = restarts.get();
+        let mut h1 = reg.register("foo").await.unwrap();
+        assert_eq!(h1.next().await, Some(1));
+
+        reg.send("foo", Request::Stall(Duration::from_secs(3600)))
+            .await;
+        let start = Instant::now();
+        assert_eq!(h1.next().await, Some(1));
+        assert!(start.elapsed() > STALL_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(reg.is_ready());
+
+        // The stalled actor was replaced once, not again and again.
+        tokio::time::sleep(STALL_TIMEOUT * 3).await;
+        assert_eq!(restarts.get(), before + 1);
+    }
+
+    fn visitor(id: &str) -> Viewer {
+        Viewer::Visitor(id.into())
+    }
+
+    #[tokio::test]
+    async fn reregisters_once_when_next_is_cancelled() {
+        // Counting visitors, so that registering the same handle twice would
+        // count it as a duplicate.
+        let reg = Registry::with_config(Config {
+            count_mode: CountMode::Visitors,
+            ..Config::default()
+        });
+        let mut h1 = reg.register_as("foo", visitor("a")).await.unwrap();
+        assert_eq!(h1.next().await, Some(1));
+
+        reg.send("foo", Request::Panic).await;
+        // Poll next() once at a time, dropping it part-way through
+        // re-registering.
+        for _ in 0..10 {
+            let _ = tokio::time::timeout(Duration::ZERO, h1.next()).await;
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(reg.total().await, Some(1));
+        let _h2 = reg.register_as("foo", visitor("b")).await.unwrap();
+        assert_eq!(h1.next().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn counts_visitors() {
+        let reg = Registry::with_config(Config {
+            count_mode: CountMode::Visitors,
+            ..Config::default()
+        });
+        let mut h1 = reg.register_as("foo", visitor("a")).await.unwrap();
+        assert_eq!(h1.next().await, Some(1));
+        let mut h2 = reg.register_as("foo", visitor("a")).await.unwrap();
+        assert_eq!(h2.next().await, Some(1));
+        let mut h3 = reg.register_as("foo", visitor("b")).await.unwrap();
+        assert_eq!(h3.next().await, Some(2));
+        let mut h4 = reg.register("foo").await.unwrap();
+        assert_eq!(h4.next().await, Some(3));
+        assert_eq!(h1.next().await, Some(3));
+
+        // Wait for the shard to process the closes before checking counts.
+        h1.close().await;
+        h4.close().await;
+        reg.local_counts().await.unwrap();
+        assert_eq!(h2.next().await, Some(2));
+        h2.close().await;
+        reg.local_counts().await.unwrap();
+        assert_eq!(h3.next().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn does_not_count_observers() {
+        let reg = Registry::new();
+        let mut obs = reg.register_as("foo", Viewer::Observer).await.unwrap();
+        assert_eq!(obs.next().await, Some(0));
+        assert!(reg.local_counts().await.unwrap().is_empty());
+        assert!(reg.peaks("foo").await.is_none());
+
+        let h1 = reg.register("foo").await.unwrap();
+        assert_eq!(obs.next().await, Some(1));
+        let mut obs2 = reg.register_as("foo", Viewer::Observer).await.unwrap();
+        assert_eq!(obs2.next().await, Some(1));
+        h1.close().await;
+        assert_eq!(obs.next().await, Some(0));
+
+        obs.close().await;
+        obs2.close().await;
+        let mut h2 = reg.register("foo").await.unwrap();
+        assert_eq!(h2.next().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn adds_peer_counts() {
+        let reg = Registry::new();
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let mut h1 = reg.register("foo").await.unwrap();
+        assert_eq!(1, h1.next().await.unwrap());
+
+        reg.peer_counts(
+            peer,
+            HashMap::from([("foo".to_string(), 3)]),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+        assert_eq!(4, h1.next().await.unwrap());
+        assert_eq!(
+            HashMap::from([("foo".to_string(), 1)]),
+            reg.local_counts().await.unwrap()
+        );
+        reg.peer_counts(
+            peer,
+            HashMap::from([("bar".to_string(), 2)]),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(6), reg.total().await);
+
+        reg.peer_counts(
+            peer,
+            HashMap::from([("foo".to_string(), 0)]),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, h1.next().await.unwrap());
+        reg.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gets_counts_without_registering() {
+        let reg = Registry::with_config(Config {
+            shards: 4,
+            ..Config::default()
+        });
+        let _h1 = reg.register("foo").await.unwrap();
+        let _h2 = reg.register("foo").await.unwrap();
+        let _h3 = reg.register("bar").await.unwrap();
+        let keys = ["bar", "baz", "foo"].map(String::from);
+        let counts: Vec<u64> = reg
+            .counts(&keys)
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.count)
+            .collect();
+        assert_eq!(counts, [1, 0, 2]);
+        assert_eq!(reg.counts(&keys).await.unwrap()[2].version, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_versions_increasing() {
+        let reg = Registry::new();
+        let keys = ["foo".to_string()];
+        let h = reg.register("foo").await.unwrap();
+        assert_eq!(reg.counts(&keys).await.unwrap()[0].version, 1);
+        drop(h);
+        let emptied = reg.counts(&keys).await.unwrap()[0];
+        assert_eq!(
+            emptied,
+            Count {
+                count: 0,
+                version: 2
+            }
+        );
+
+        // A count only from a peer gets a newer version when it changes.
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let ttl = Duration::from_secs(5);
+        reg.peer_counts(peer, HashMap::from([(keys[0].clone(), 3)]), ttl)
+            .await
+            .unwrap();
+        let counts = reg.counts(&keys).await.unwrap();
+        assert_eq!(counts[0].count, 3);
+        assert!(counts[0].version > emptied.version);
+
+        // And so does a page re-created for the key.
+        let _h = reg.register("foo").await.unwrap();
+        let recreated = reg.counts(&keys).await.unwrap()[0];
+        assert_eq!(recreated.count, 4);
+        assert!(recreated.version > counts[0].version);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_leases() {
+        let reg = Registry::new();
+        let ttl = Duration::from_secs(10);
+        let mut l1 = reg.lease("foo", "a", Viewer::Anonymous, ttl).await.unwrap();
+        assert_eq!(
+            l1.current(),
+            Count {
+                count: 1,
+                version: 1
+            }
+        );
+        let l2 = reg.lease("foo", "b", Viewer::Anonymous, ttl).await.unwrap();
+        assert_eq!(l1.changed().await.unwrap().count, 2);
+        assert_eq!(l2.current().version, 2);
+
+        // Renewing doesn't count again, but keeps the lease alive.
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let l1 = reg.lease("foo", "a", Viewer::Anonymous, ttl).await.unwrap();
+        assert_eq!(l1.current().count, 2);
+        tokio::time::sleep(Duration::from_secs(7)).await;
+        assert_eq!(
+            l1.current(),
+            Count {
+                count: 1,
+                version: 3
+            }
+        );
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(reg.local_counts().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_silent_peers() {
+        let reg = Registry::new();
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let mut h1 = reg.register("foo").await.unwrap();
+        assert_eq!(1, h1.next().await.unwrap());
+
+        reg.peer_counts(
+            peer,
+            HashMap::from([("foo".to_string(), 2)]),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(3, h1.next().await.unwrap());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(1, h1.next().await.unwrap());
+        reg.stop().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_peers_across_shards() {
+        let reg = Registry::with_config(Config {
+            shards: 4,
+            ..Config::default()
+        });
+        // Two keys on different shards, one from each peer.
+        let a = "key-0".to_string();
+        let b = (1..)
+            .map(|i| format!("key-{i}"))
+            .find(|k| reg.shard_index(k) != reg.shard_index(&a))
+            .unwrap();
+        let ttl = Duration::from_secs(5);
+        let p1 = "127.0.0.1:1".parse().unwrap();
+        let p2 = "127.0.0.1:2".parse().unwrap();
+        reg.peer_counts(p1, HashMap::from([(a.clone(), 1)]), ttl)
+            .await
+            .unwrap();
+        reg.peer_counts(p2, HashMap::from([(b.clone(), 1)]), ttl)
+            .await
+            .unwrap();
+        // Wait for the shards to process the counts.
+        reg.local_counts().await.unwrap();
+        assert_eq!(reg.cluster_peers(), 2);
+
+        // The same peer on several shards counts once.
+        reg.peer_counts(p1, HashMap::from([(b, 2)]), ttl)
+            .await
+            .unwrap();
+        reg.local_counts().await.unwrap();
+        assert_eq!(reg.cluster_peers(), 2);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        reg.local_counts().await.unwrap();
+        assert_eq!(reg.cluster_peers(), 0);
+    }
+}
+
+/// Who a handle is for, deciding how it's counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Viewer {
+    /// A viewer of its own.
+    Anonymous,
+
+    /// A browser identified by a visitor ID. If counting visitors, all
+    /// handles of the same visitor for a key count as one viewer. Otherwise
+    /// this is the same as `Anonymous`.
+    Visitor(Arc<str>),
+
+    /// Receives counts, but is not counted.
+    Observer,
+}
+
+/// A key's count, and a version that changes whenever the count does.
+///
+/// Versions of a key only go up, also when its last handle goes away or it
+/// only has peer counts, so clients can use them to order updates. They
+/// start over when the server restarts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Count {
+    pub count: u64,
+    pub version: u64,
 }
 
 #[derive(Debug)]
 pub struct Handle {
     id: u64,
 
-    // TODO: optimize this so that not all handles keep a
-    // copy of the key.
-    key: String,
+    /// Interned key, shared by all handles for the same key.
+    key: Arc<str>,
+
+    /// Who the handle is counted as.
+    viewer: Viewer,
 
-    ch: mpsc::Receiver<u64>,
+    ch: watch::Receiver<Count>,
     control: mpsc::Sender<Request>,
+
+    /// Re-registration waiting for the restarted shard, kept across calls to
+    /// `next()` so that a cancelled call doesn't register again.
+    pending: Option<oneshot::Receiver<watch::Receiver<Count>>>,
+
+    /// Set once unregistered, so that drop doesn't unregister again.
+    closed: bool,
 }
 
 impl Handle {
+    /// Wait for the count to change, and return the new count.
+    ///
+    /// Counts are not queued. If the count changed several times since the
+    /// last call, only the latest count is returned.
+    ///
+    /// If the shard of the key was restarted, the handle registers again, and
+    /// the count from the restarted shard is returned. `None` is returned only
+    /// if the registry is gone.
     pub async fn next(&mut self) -> Option<u64> {
-        self.ch.recv().await
+        loop {
+            let rx = match &mut self.pending {
+                Some(rx) => rx,
+                None => {
+                    if self.ch.changed().await.is_ok() {
+                        return Some(self.ch.borrow_and_update().count);
+                    }
+                    debug!("Count channel closed, re-registering {}", self.id);
+                    let (tx, rx) = oneshot::channel();
+                    let req =
+                        Request::Reregister(self.id, self.key.clone(), self.viewer.clone(), tx);
+                    self.control.send(req).await.ok()?;
+                    self.pending.insert(rx)
+                }
+            };
+            let ch = rx.await;
+            self.pending = None;
+            self.ch = ch.ok()?;
+            self.ch.mark_changed();
+        }
     }
 
-    pub async fn close(self) {
-        let ch = self.control.clone();
-        ch.send(Request::Unregister(self))
-            .await
-            .expect("failed to send unregister");
+    pub async fn close(mut self) {
+        self.closed = true;
+        let req = Request::Unregister(self.id, self.key.clone(), self.viewer.clone());
+        if let Err(err) = self.control.send(req).await {
+            debug!("Registry gone when closing handle: {err}");
+        }
     }
 }
 
+/// A registration that lasts until it expires, instead of until it's closed,
+/// for clients without a connection to close. See [`Registry::lease`].
 #[derive(Debug)]
-pub enum Request {
-    Register(String, mpsc::Sender<Handle>),
-    Unregister(Handle),
-    #[cfg(test)]
-    Stop,
+pub struct Lease {
+    ch: watch::Receiver<Count>,
 }
 
-pub struct Registry {
-    ch: mpsc::Sender<Request>,
-    _join: tokio::task::JoinHandle<()>,
-}
+impl Lease {
+    /// Get the current count.
+    pub fn current(&self) -> Count {
+        *self.ch.borrow()
+    }
 
-impl Registry {
-    pub fn new() -> Registry {
-        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
-        Registry {
-            ch: tx.clone(),
-            _join: tokio::spawn(async move { Self::main(tx.clone(), rx).await }),
-        }
+    /// Wait for the count to change, and return the new count. `None` is
+    /// returned if the lease's shard was restarted or the registry is gone.
+    pub async fn changed(&mut self) -> Option<Count> {
+        self.ch.changed().await.ok()?;
+        Some(*self.ch.borrow_and_update())
     }
+}
 
-    async fn publish(txm: &HashMap<u64, mpsc::Sender<u64>>, set: &HashSet<u64>, v: u64) {
-        for id in set {
-            if false {
-                trace!("Sending to id {}", id);
+impl Drop for Handle {
+    /// Unregister handles that were dropped without calling `close()`.
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        let req = Request::Unregister(self.id, self.key.clone(), self.viewer.clone());
+        match self.control.try_send(req) {
+            Ok(()) => {}
+            Err(TrySendError::Full(req)) => {
+                let Ok(rt) = tokio::runtime::Handle::try_current() else {
+                    warn!("Failed to unregister dropped handle outside of runtime");
+                    return;
+                };
+                let control = self.control.clone();
+                rt.spawn(async move {
+                    if let Err(err) = control.send(req).await {
+                        warn!("Failed to unregister dropped handle: {err}");
+                    }
+                });
             }
-            let Some(e) = txm.get(id) else {
-                warn!("Wanted to publish to channel ID {id}, but missing");
-                continue;
-            };
-            if let Err(err) = e.try_send(v) {
-                warn!("Failed to publish to channel ID {}: {}", id, err);
-                continue;
+            Err(TrySendError::Closed(_)) => {
+                debug!("Registry gone when dropping handle");
             }
         }
-        debug!("Sent to all");
     }
+}
 
-    async fn main(tx: mpsc::Sender<Request>, mut rx: mpsc::Receiver<Request>) {
-        let mut tx_map: HashMap<u64, mpsc::Sender<u64>> = HashMap::new();
-        let mut key_map: HashMap<String, HashSet<u64>> = HashMap::new();
-        let mut current_id = 0;
-        loop {
-            match rx.recv().await {
-                Some(Request::Register(key, ch)) => {
-                    debug!("Registering");
-                    REGISTRATIONS.inc();
-                    let (ctx, crx) = mpsc::channel(CHANNEL_SIZE);
+/// Subscribers of one key.
+struct Page {
+    /// Latest count, shared by all handles for the key.
+    tx: watch::Sender<Count>,
+    ids: HashSet<u64>,
 
-                    let entry = key_map.entry(key.to_owned()).or_default();
-                    current_id += 1;
-                    let id = current_id;
-                    entry.insert(id);
+    /// Number of handles per visitor ID, for visitors with any handles.
+    visitors: HashMap<Arc<str>, u32>,
 
-                    let handle = Handle {
-                        id,
-                        key: key.clone(),
-                        ch: crx,
-                        control: tx.clone(),
-                    };
+    /// Number of handles of visitors that have other handles too.
+    duplicates: usize,
 
-                    tx_map.insert(id, ctx);
-                    debug!(
-                        "After register: {} active connections (key {key})",
-                        tx_map.len()
-                    );
+    /// Number of handles receiving counts without being counted.
+    observers: usize,
 
-                    // Confirm that all this key's subscribers are all there.
-                    let removed: Vec<_> = entry
-                        .extract_if(|id| tx_map.get(id).map(|s| s.is_closed()).unwrap_or(true))
-                        .collect();
-                    for removed in removed {
-                        tx_map.remove(&removed);
-                    }
+    /// If the key has its own label in per-page metrics. Only keys with
+    /// counted handles can have one.
+    labeled: bool,
 
-                    let count = entry.len();
-                    TOTAL_ACTIVE.set(i64::try_from(tx_map.len()).unwrap());
-                    match i64::try_from(count) {
-                        Ok(v) => PAGE_ACTIVE.with_label_values(&[&key]).set(v),
-                        Err(e) => error!("Failed to convert {count} to i64 in register: {e}"),
-                    }
-                    Self::publish(&tx_map, entry, u64::try_from(count).unwrap()).await;
-                    if let Err(err) = ch.send(handle).await {
-                        warn!("Failed to send handle back during register(): {}", err);
-                    };
+    /// Version counter of the shard, shared by its pages.
+    versions: Arc<AtomicU64>,
+}
+
+impl Page {
+    fn new(versions: Arc<AtomicU64>) -> Self {
+        // Starting from the latest version of the shard keeps the key's
+        // versions going up, even if it had a page before.
+        let count = Count {
+            count: 0,
+            version: versions.load(Ordering::Relaxed),
+        };
+        Page {
+            tx: watch::Sender::new(count),
+            ids: HashSet::new(),
+            visitors: HashMap::new(),
+            duplicates: 0,
+            observers: 0,
+            labeled: false,
+            versions,
+        }
+    }
+
+    /// Number of local viewers of the key.
+    fn count(&self) -> u64 {
+        u64::try_from(self.ids.len() - self.duplicates).unwrap()
+    }
+
+    /// Number of local handles for the key.
+    fn sessions(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Publish a new count to all handles of the key.
+    ///
+    /// This is O(1) no matter how many handles there are. Handles are only
+    /// woken if the count actually changed, and if so true is returned.
+    fn publish(&self, count: u64) -> bool {
+        self.tx.send_if_modified(|old| {
+            if old.count == count {
+                return false;
+            }
+            old.count = count;
+            old.version = self.versions.fetch_add(1, Ordering::Relaxed) + 1;
+            true
+        })
+    }
+}
+
+/// The pages of one shard.
+///
+/// Dropping it removes its share of the active session metrics, so that a
+/// restarted shard starts from zero.
+struct Pages {
+    key_map: HashMap<Arc<str>, Page>,
+    labels: Arc<PageLabels>,
+    mode: CountMode,
+
+    /// Number of handles across all pages.
+    active: usize,
+
+    /// Latest version of any count of the shard. Kept across restarts, so
+    /// that versions only go up.
+    versions: Arc<AtomicU64>,
+}
+
+impl Pages {
+    fn new(labels: Arc<PageLabels>, mode: CountMode, versions: Arc<AtomicU64>) -> Self {
+        Pages {
+            key_map: HashMap::new(),
+            labels,
+            mode,
+            active: 0,
+            versions,
+        }
+    }
+
+    /// Bump the version of the shard, for a count change of a key without a
+    /// page.
+    fn bump_version(&self) {
+        self.versions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add handle `id` to a key. Returns the interned key, the viewer to
+    /// unregister as, and a receiver of its counts.
+    ///
+    /// The receiver is marked changed, so that it gets the current count even
+    /// if adding the handle didn't change it.
+    fn add(
+        &mut self,
+        key: &str,
+        id: u64,
+        viewer: Viewer,
+    ) -> (Arc<str>, Viewer, watch::Receiver<Count>) {
+        let viewer = match viewer {
+            Viewer::Visitor(_) if self.mode == CountMode::Handles => Viewer::Anonymous,
+            viewer => viewer,
+        };
+        let key = match self.key_map.get_key_value(key) {
+            Some((key, _)) => key.clone(),
+            None => Arc::from(key),
+        };
+        let page = self
+            .key_map
+            .entry(key.clone())
+            .or_insert_with(|| Page::new(self.versions.clone()));
+        if viewer == Viewer::Observer {
+            page.observers += 1;
+            debug!("After register: {} observers of key {key}", page.observers);
+        } else {
+            if page.ids.is_empty() {
+                page.labeled = self.labels.claim(&key);
+            }
+            page.ids.insert(id);
+            if let Viewer::Visitor(visitor) = &viewer {
+                let handles = page.visitors.entry(visitor.clone()).or_insert(0);
+                if *handles > 0 {
+                    page.duplicates += 1;
+                }
+                *handles += 1;
+            }
+            self.active += 1;
+            TOTAL_ACTIVE.inc();
+            debug!(
+                "After register: {} active connections (key {key})",
+                self.active
+            );
+
+            if !page.labeled {
+                PAGE_ACTIVE.with_label_values(&[OTHER_PAGES_LABEL]).inc();
+            } else {
+                let count = page.sessions();
+                match i64::try_from(count) {
+                    Ok(v) => PAGE_ACTIVE.with_label_values(&[&key]).set(v),
+                    Err(e) => error!("Failed to convert {count} to i64 in register: {e}"),
+                }
+            }
+        }
+        let mut rx = page.tx.subscribe();
+        rx.mark_changed();
+        (key, viewer, rx)
+    }
+
+    /// Remove handle `id` from a key. Returns the number of viewers left for
+    /// the key, or `None` if the handle wasn't there.
+    ///
+    /// Keys are removed when their last handle is, including observers.
+    fn remove(&mut self, key: &str, id: u64, viewer: &Viewer) -> Option<u64> {
+        let page = self.key_map.get_mut(key)?;
+        if *viewer == Viewer::Observer {
+            page.observers = page.observers.checked_sub(1)?;
+        } else {
+            if !page.ids.remove(&id) {
+                return None;
+            }
+            if let Viewer::Visitor(visitor) = viewer {
+                match page.visitors.get_mut(visitor) {
+                    Some(1) => {
+                        page.visitors.remove(visitor);
+                    }
+                    Some(handles) => {
+                        *handles -= 1;
+                        page.duplicates -= 1;
+                    }
+                    None => warn!("CAN'T HAPPEN: Unregister of unknown visitor"),
+                }
+            }
+            self.active -= 1;
+            TOTAL_ACTIVE.dec();
+            debug!("After unregister: {} active connections", self.active);
+
+            let remaining = page.sessions();
+            if !page.labeled {
+                PAGE_ACTIVE.with_label_values(&[OTHER_PAGES_LABEL]).dec();
+            } else if remaining > 0 {
+                match i64::try_from(remaining) {
+                    Ok(v) => PAGE_ACTIVE.with_label_values(&[key]).set(v),
+                    Err(e) => error!("Failed to convert {remaining} to i64 in unregister: {e}"),
+                }
+            } else {
+                self.labels.release(key);
+                page.labeled = false;
+            }
+        }
+        let viewers = page.count();
+        if page.ids.is_empty() && page.observers == 0 {
+            self.key_map.remove(key);
+        }
+        Some(viewers)
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        TOTAL_ACTIVE.sub(i64::try_from(self.active).unwrap_or(i64::MAX));
+        for (key, page) in &self.key_map {
+            if page.labeled {
+                self.labels.release(key);
+            } else {
+                let count = i64::try_from(page.sessions()).unwrap_or(i64::MAX);
+                PAGE_ACTIVE
+                    .with_label_values(&[OTHER_PAGES_LABEL])
+                    .sub(count);
+            }
+        }
+    }
+}
+
+/// Label for pages without their own label in per-page metrics.
+const OTHER_PAGES_LABEL: &str = "other";
+
+/// Which keys get their own label in per-page metrics, shared by all shards.
+///
+/// Every label is a separate time series, so this is capped to keep clients
+/// from being able to create unbounded numbers of them.
+struct PageLabels {
+    limit: usize,
+    prefixes: Vec<String>,
+
+    /// Number of keys currently labeled.
+    used: AtomicUsize,
+}
+
+impl PageLabels {
+    fn new(config: &Config) -> Self {
+        PageLabels {
+            limit: config.page_metrics_limit,
+            prefixes: config.page_metrics_prefixes.clone(),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Try to get a label for a new key. If true is returned, the label must
+    /// be released when the key goes away.
+    fn claim(&self, key: &str) -> bool {
+        if !self.prefixes.is_empty() && !self.prefixes.iter().any(|p| key.starts_with(p)) {
+            return false;
+        }
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used < self.limit).then_some(used + 1)
+            })
+            .is_ok()
+    }
+
+    /// Remove the metrics of a labeled key that went away. This includes its
+    /// peaks, which are still kept for `/livecount/peaks`, and exported again
+    /// if the key gets a label again.
+    fn release(&self, key: &str) {
+        let _ = PAGE_ACTIVE.remove_label_values(&[key]);
+        let _ = PAGE_PEAK.remove_label_values(&[key, "all"]);
+        let _ = PAGE_PEAK.remove_label_values(&[key, "day"]);
+        self.used.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A page and its current number of viewers, for ranking pages.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct TopPage {
+    pub url: String,
+    pub count: u64,
+}
+
+/// Sort pages by most viewers first, and keep the top `n`.
+fn rank(pages: &mut Vec<TopPage>, n: usize) {
+    let order = |a: &TopPage, b: &TopPage| b.count.cmp(&a.count).then_with(|| a.url.cmp(&b.url));
+    if n == 0 {
+        pages.clear();
+        return;
+    }
+    if pages.len() > n {
+        pages.select_nth_unstable_by(n - 1, order);
+        pages.truncate(n);
+    }
+    pages.sort_unstable_by(order);
+}
+
+/// Check if a key is a URL on the given host.
+fn key_has_host(key: &str, host: &str) -> bool {
+    url::Url::parse(key).is_ok_and(|url| url.host_str() == Some(host))
+}
+
+/// Publish the count of a key that a handle was just added to.
+fn publish_added(
+    pages: &Pages,
+    peers: &Peers,
+    peaks: &mut Peaks,
+    changes: &watch::Sender<u64>,
+    key: &Arc<str>,
+) {
+    let page = &pages.key_map[key];
+    let total = page.count() + peers.count(key);
+    if page.publish(total) {
+        notify_change(changes);
+    }
+    peaks.observe(key, total, unix_time(), page.labeled);
+}
+
+/// Remove a handle from a key, and publish the key's new count.
+fn unregister(
+    pages: &mut Pages,
+    peers: &Peers,
+    changes: &watch::Sender<u64>,
+    key: &str,
+    id: u64,
+    viewer: &Viewer,
+) {
+    let Some(remaining) = pages.remove(key, id, viewer) else {
+        // Handles from before a restart may unregister without having
+        // registered again.
+        debug!("Unregister of unknown handle {id} for key {key}");
+        return;
+    };
+    let changed = match pages.key_map.get(key) {
+        Some(page) => page.publish(remaining + peers.count(key)),
+        // A removed key changes the top pages, even though there is no one
+        // left to publish its count to.
+        None => {
+            pages.bump_version();
+            true
+        }
+    };
+    if changed {
+        notify_change(changes);
+    }
+}
+
+/// Tell watchers of `Registry::changes()` that some count changed.
+fn notify_change(changes: &watch::Sender<u64>) {
+    changes.send_modify(|generation| *generation = generation.wrapping_add(1));
+}
+
+#[derive(Debug)]
+pub(crate) enum Request {
+    Register(String, Viewer, oneshot::Sender<Handle>),
+    /// Add an existing handle again, after the shard was restarted.
+    Reregister(
+        u64,
+        Arc<str>,
+        Viewer,
+        oneshot::Sender<watch::Receiver<Count>>,
+    ),
+    Unregister(u64, Arc<str>, Viewer),
+    /// Add or renew a lease on a key, valid until the given deadline.
+    Lease(
+        String,
+        String,
+        Viewer,
+        Instant,
+        oneshot::Sender<watch::Receiver<Count>>,
+    ),
+    /// Get the per-key counts of local handles, for gossiping to peers.
+    LocalCounts(oneshot::Sender<HashMap<String, u64>>),
+    /// Per-key counts from a cluster peer, valid until the given deadline.
+    PeerCounts(SocketAddr, HashMap<String, u64>, Instant),
+    /// Get the count history of a key.
+    History(String, oneshot::Sender<Vec<Sample>>),
+    /// Get the peak counts of a key.
+    Peaks(String, oneshot::Sender<Option<PagePeaks>>),
+    /// Get the top N keys by count, optionally only URLs on a host.
+    Top(usize, Option<String>, oneshot::Sender<Vec<TopPage>>),
+    /// Get the sum of counts of all keys, including peer counts.
+    Total(oneshot::Sender<u64>),
+    /// Get the counts of keys, including peer counts.
+    Counts(Vec<String>, oneshot::Sender<Vec<Count>>),
+    #[cfg(test)]
+    Stop,
+    #[cfg(test)]
+    Panic,
+    #[cfg(test)]
+    Stall(Duration),
+}
+
+/// One sample of a key's count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Sample {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub count: u64,
+}
+
+/// Bounded time series of counts per key.
+struct History {
+    samples: usize,
+    keys: HashMap<Arc<str>, VecDeque<Sample>>,
+}
+
+impl History {
+    fn new(samples: usize) -> Self {
+        History {
+            samples,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Add a sample for every key. Keys with history but no count get a
+    /// zero, and keys that have been zero for the whole history are dropped.
+    fn sample(&mut self, time: u64, mut counts: HashMap<Arc<str>, u64>) {
+        for (key, samples) in self.keys.iter_mut() {
+            let count = counts.remove(key).unwrap_or(0);
+            samples.push_back(Sample { time, count });
+            while samples.len() > self.samples {
+                samples.pop_front();
+            }
+        }
+        self.keys
+            .retain(|_, samples| samples.iter().any(|s| s.count > 0));
+        for (key, count) in counts {
+            if count > 0 {
+                self.keys
+                    .entry(key)
+                    .or_default()
+                    .push_back(Sample { time, count });
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Vec<Sample> {
+        self.keys
+            .get(key)
+            .map(|samples| samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// A peak count, and when it happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Peak {
+    pub count: u64,
+
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+}
+
+/// Peak counts of a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct PagePeaks {
+    pub all_time: Peak,
+
+    /// Peak of the current UTC day, if there were viewers today.
+    pub today: Option<Peak>,
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Peak counts per key.
+///
+/// Peaks outlive the pages' handles, so they're capped rather than removed
+/// with the pages. Their metrics are only exported while the pages have
+/// labels, i.e. viewers; see [`PageLabels::release`].
+struct Peaks {
+    reset_daily: bool,
+
+    /// Max number of keys.
+    limit: usize,
+
+    /// Current UTC day, as days since the Unix epoch.
+    day: u64,
+    keys: HashMap<Arc<str>, PagePeaks>,
+}
+
+impl Peaks {
+    fn new(reset_daily: bool, limit: usize) -> Self {
+        Peaks {
+            reset_daily,
+            limit,
+            day: unix_time() / SECS_PER_DAY,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Record a count, updating peaks if it's a new max.
+    ///
+    /// Metrics are only updated for keys with their own label.
+    fn observe(&mut self, key: &Arc<str>, count: u64, time: u64, labeled: bool) {
+        self.roll(time);
+        if count == 0 {
+            return;
+        }
+        let peak = Peak { count, time };
+        if self.keys.len() >= self.limit && !self.keys.contains_key(key) {
+            self.evict();
+        }
+        let peaks = self.keys.entry(key.clone()).or_insert(PagePeaks {
+            all_time: peak,
+            today: None,
+        });
+        if count > peaks.all_time.count {
+            peaks.all_time = peak;
+        }
+        if peaks.today.is_none_or(|today| count > today.count) {
+            peaks.today = Some(peak);
+            if labeled {
+                set_peak_metric(key, "day", count);
+            }
+        }
+        if labeled {
+            set_peak_metric(key, "all", peaks.all_time.count);
+        }
+    }
+
+    /// Start a new day, if the UTC day has changed.
+    fn roll(&mut self, time: u64) {
+        let day = time / SECS_PER_DAY;
+        if day == self.day {
+            return;
+        }
+        debug!("New UTC day, resetting daily peaks");
+        self.day = day;
+        for (key, peaks) in self.keys.iter_mut() {
+            peaks.today = None;
+            let _ = PAGE_PEAK.remove_label_values(&[key, "day"]);
+            if self.reset_daily {
+                let _ = PAGE_PEAK.remove_label_values(&[key, "all"]);
+            }
+        }
+        if self.reset_daily {
+            self.keys.clear();
+        }
+    }
+
+    /// Forget the keys with the lowest all-time peaks, oldest first. Makes
+    /// room for a tenth of the limit at once, so that this is rare.
+    fn evict(&mut self) {
+        let keep = self.limit.saturating_sub((self.limit / 10).max(1));
+        let mut keys: Vec<(Peak, Arc<str>)> = self
+            .keys
+            .iter()
+            .map(|(key, peaks)| (peaks.all_time, key.clone()))
+            .collect();
+        keys.sort_unstable_by_key(|(peak, _)| (peak.count, peak.time));
+        let evicted = keys.len().saturating_sub(keep);
+        debug!("Forgetting peaks of {evicted} keys");
+        for (_, key) in keys.into_iter().take(evicted) {
+            self.keys.remove(&key);
+            let _ = PAGE_PEAK.remove_label_values(&[&key, "all"]);
+            let _ = PAGE_PEAK.remove_label_values(&[&key, "day"]);
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<PagePeaks> {
+        self.keys.get(key).copied()
+    }
+}
+
+fn set_peak_metric(key: &str, period: &str, count: u64) {
+    match i64::try_from(count) {
+        Ok(v) => PAGE_PEAK.with_label_values(&[key, period]).set(v),
+        Err(e) => error!("Failed to convert {count} to i64 for peak: {e}"),
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Peers with unexpired counts in each shard, shared by all shards.
+///
+/// A peer's keys are spread across shards, so the cluster peer metric counts
+/// the distinct peers of all shards together.
+struct PeerSets {
+    shards: std::sync::Mutex<Vec<HashSet<SocketAddr>>>,
+}
+
+impl PeerSets {
+    fn new(shards: usize) -> Self {
+        PeerSets {
+            shards: std::sync::Mutex::new(vec![HashSet::new(); shards]),
+        }
+    }
+
+    /// Replace the peers of a shard, and update the metric.
+    fn set(&self, shard: usize, peers: HashSet<SocketAddr>) {
+        let mut shards = self.shards.lock().unwrap();
+        shards[shard] = peers;
+        CLUSTER_PEERS.set(i64::try_from(Self::count(&shards)).unwrap_or(i64::MAX));
+    }
+
+    fn count(shards: &[HashSet<SocketAddr>]) -> usize {
+        shards.iter().flatten().collect::<HashSet<_>>().len()
+    }
+
+    /// Number of distinct peers across all shards.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        Self::count(&self.shards.lock().unwrap())
+    }
+}
+
+/// Per-key counts reported by cluster peers.
+struct Peers {
+    counts: HashMap<String, HashMap<SocketAddr, (u64, Instant)>>,
+
+    /// Where this shard's peers are reported, and the shard's index there.
+    sets: Arc<PeerSets>,
+    shard: usize,
+}
+
+impl Peers {
+    /// Create the peer counts of a shard, which start out empty.
+    fn new(sets: Arc<PeerSets>, shard: usize) -> Self {
+        sets.set(shard, HashSet::new());
+        Peers {
+            counts: HashMap::new(),
+            sets,
+            shard,
+        }
+    }
+
+    /// Sum of all peer counts for a key.
+    fn count(&self, key: &str) -> u64 {
+        self.counts
+            .get(key)
+            .map(|peers| peers.values().map(|(count, _)| count).sum())
+            .unwrap_or(0)
+    }
+
+    /// Sum of all peer counts for all keys.
+    fn total(&self) -> u64 {
+        self.counts
+            .values()
+            .flat_map(|peers| peers.values().map(|(count, _)| count))
+            .sum()
+    }
+
+    /// Store new counts from a peer, returning the keys whose counts changed.
+    fn update(
+        &mut self,
+        peer: SocketAddr,
+        counts: HashMap<String, u64>,
+        expires: Instant,
+    ) -> Vec<String> {
+        let mut changed = Vec::new();
+        for (key, count) in counts {
+            let old = if count == 0 {
+                let old = self.counts.get_mut(&key).and_then(|p| p.remove(&peer));
+                if self.counts.get(&key).is_some_and(|p| p.is_empty()) {
+                    self.counts.remove(&key);
+                }
+                old
+            } else {
+                self.counts
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(peer, (count, expires))
+            };
+            if old.map(|(c, _)| c).unwrap_or(0) != count {
+                changed.push(key);
+            }
+        }
+        self.update_metric();
+        changed
+    }
+
+    /// Drop expired counts, returning the keys whose counts changed.
+    fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut changed = Vec::new();
+        self.counts.retain(|key, peers| {
+            let before = peers.len();
+            peers.retain(|peer, (_, expires)| {
+                if *expires > now {
+                    return true;
+                }
+                debug!("Count for {key} from peer {peer} expired");
+                false
+            });
+            if peers.len() != before {
+                changed.push(key.clone());
+            }
+            !peers.is_empty()
+        });
+        if !changed.is_empty() {
+            self.update_metric();
+        }
+        changed
+    }
+
+    fn update_metric(&self) {
+        let peers = self.counts.values().flat_map(|p| p.keys()).copied();
+        self.sets.set(self.shard, peers.collect());
+    }
+}
+
+/// Lease registrations of one shard.
+#[derive(Default)]
+struct Leases {
+    /// Leases by key and lease ID.
+    leases: HashMap<(String, String), LeaseEntry>,
+}
+
+struct LeaseEntry {
+    /// ID of the lease's registration in its page.
+    id: u64,
+
+    /// Interned key of the page.
+    key: Arc<str>,
+
+    viewer: Viewer,
+    expires: Instant,
+}
+
+impl Leases {
+    /// Drop expired leases, returning their keys, IDs and viewers.
+    fn expire(&mut self, now: Instant) -> Vec<(Arc<str>, u64, Viewer)> {
+        let mut expired = Vec::new();
+        self.leases.retain(|_, entry| {
+            if entry.expires > now {
+                return true;
+            }
+            debug!("Lease {} for key {} expired", entry.id, entry.key);
+            TIMEOUTS.with_label_values(&["lease"]).inc();
+            expired.push((entry.key.clone(), entry.id, entry.viewer.clone()));
+            false
+        });
+        expired
+    }
+}
+
+/// Error returned when the registry has shut down.
+#[derive(Debug)]
+pub struct Closed;
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "registry is shut down")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// One actor owning the handles for a subset of keys.
+struct Shard {
+    ch: mpsc::Sender<Request>,
+    state: Arc<ShardState>,
+
+    /// The supervisor of the actor.
+    _join: tokio::task::JoinHandle<()>,
+}
+
+/// State of a shard that outlives restarts of its actor.
+struct ShardState {
+    /// Index of the shard in the registry.
+    index: usize,
+
+    /// Latest version of any count of the shard.
+    versions: Arc<AtomicU64>,
+
+    /// ID of the next handle. Kept across restarts so that re-registering
+    /// handles keep unique IDs.
+    next_id: AtomicU64,
+
+    epoch: Instant,
+
+    /// Last time the actor made progress, in ms since `epoch`.
+    heartbeat: AtomicU64,
+
+    /// If the actor is running.
+    ready: AtomicBool,
+}
+
+impl ShardState {
+    fn new(index: usize) -> Self {
+        ShardState {
+            index,
+            versions: Arc::new(AtomicU64::new(0)),
+            next_id: AtomicU64::new(1),
+            epoch: Instant::now(),
+            heartbeat: AtomicU64::new(0),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        u64::try_from((Instant::now() - self.epoch).as_millis()).unwrap_or(u64::MAX)
+    }
+
+    fn beat(&self) {
+        self.heartbeat.store(self.now(), Ordering::Relaxed);
+    }
+
+    /// Check if the actor has made no progress for `STALL_TIMEOUT`.
+    fn stalled(&self) -> bool {
+        let idle = self
+            .now()
+            .saturating_sub(self.heartbeat.load(Ordering::Relaxed));
+        Duration::from_millis(idle) > STALL_TIMEOUT
+    }
+
+    fn set_ready(&self, ready: bool) {
+        if self.ready.swap(ready, Ordering::Relaxed) != ready {
+            REGISTRY_READY.add(if ready { 1 } else { -1 });
+        }
+    }
+}
+
+pub struct Registry {
+    shards: Vec<Shard>,
+    hasher: RandomState,
+    config: Config,
+
+    /// Bumped whenever any count changes.
+    changes: Arc<watch::Sender<u64>>,
+
+    /// Peers of each shard, to check the cluster peer metric in tests.
+    #[cfg(test)]
+    peer_sets: Arc<PeerSets>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Registry {
+        assert!(config.shards > 0, "registry needs at least one shard");
+        let changes = Arc::new(watch::Sender::new(0));
+        let labels = Arc::new(PageLabels::new(&config));
+        let peer_sets = Arc::new(PeerSets::new(config.shards));
+        Registry {
+            shards: (0..config.shards)
+                .map(|index| {
+                    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+                    let state = Arc::new(ShardState::new(index));
+                    Shard {
+                        ch: tx.clone(),
+                        state: state.clone(),
+                        _join: tokio::spawn(Self::supervise(
+                            config.clone(),
+                            changes.clone(),
+                            labels.clone(),
+                            peer_sets.clone(),
+                            state,
+                            tx,
+                            Arc::new(Mutex::new(rx)),
+                        )),
+                    }
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            config,
+            changes,
+            #[cfg(test)]
+            peer_sets,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Watch for count changes of any key.
+    ///
+    /// The value is a generation number with no meaning other than that it
+    /// changes.
+    pub fn changes(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        // Truncation is fine, it's just a hash.
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// Run a shard's actor, restarting it if it panics or stalls.
+    ///
+    /// The restarted actor starts out with no handles. Live handles notice
+    /// that the count channel of their key closed, and register again.
+    ///
+    /// A stalled actor is only replaced once its task is dropped, at its next
+    /// await point. An actor stuck in synchronous code never gets there, so
+    /// its shard can't be recovered, and stays not ready.
+    async fn supervise(
+        config: Config,
+        changes: Arc<watch::Sender<u64>>,
+        labels: Arc<PageLabels>,
+        peer_sets: Arc<PeerSets>,
+        state: Arc<ShardState>,
+        tx: mpsc::Sender<Request>,
+        rx: Arc<Mutex<mpsc::Receiver<Request>>>,
+    ) {
+        loop {
+            state.beat();
+            let mut actor = tokio::spawn(Self::main(
+                config.clone(),
+                changes.clone(),
+                labels.clone(),
+                peer_sets.clone(),
+                state.clone(),
+                tx.clone(),
+                rx.clone(),
+            ));
+            let mut watchdog = tokio::time::interval(WATCHDOG_INTERVAL);
+            let reason = loop {
+                tokio::select! {
+                    res = &mut actor => match res {
+                        Err(e) if e.is_panic() => break "panic",
+                        _ => {
+                            debug!("Registry shard stopped");
+                            state.set_ready(false);
+                            return;
+                        }
+                    },
+                    _ = watchdog.tick() => {
+                        if state.stalled() {
+                            // Dropping the actor's state at its next await
+                            // point closes the handles' count channels.
+                            actor.abort();
+                            break "stall";
+                        }
+                    }
+                }
+            };
+            state.set_ready(false);
+            error!("Registry shard actor {reason}, restarting");
+            REGISTRY_RESTARTS.with_label_values(&[reason]).inc();
+            if reason == "stall" {
+                // The stalled actor holds the receiver until it's dropped.
+                // Starting another actor before that would only stall too.
+                if tokio::time::timeout(STALL_TIMEOUT, &mut actor)
+                    .await
+                    .is_err()
+                {
+                    error!("Registry shard actor doesn't yield, waiting for it");
+                    let _ = actor.await;
+                }
+            }
+        }
+    }
+
+    async fn main(
+        config: Config,
+        changes: Arc<watch::Sender<u64>>,
+        labels: Arc<PageLabels>,
+        peer_sets: Arc<PeerSets>,
+        state: Arc<ShardState>,
+        tx: mpsc::Sender<Request>,
+        rx: Arc<Mutex<mpsc::Receiver<Request>>>,
+    ) {
+        // The supervisor waits for a previous actor that stalled to be
+        // dropped, so this doesn't wait for long.
+        let mut rx = rx.lock().await;
+        state.set_ready(true);
+
+        let mut pages = Pages::new(labels, config.count_mode, state.versions.clone());
+        let mut peers = Peers::new(peer_sets, state.index);
+        let mut leases = Leases::default();
+        let mut history = History::new(config.history_samples);
+        // Split the limit across shards, like keys are.
+        let mut peaks = Peaks::new(
+            config.reset_peaks_daily,
+            config.peaks_limit.div_ceil(config.shards),
+        );
+        let mut expiry = tokio::time::interval(EXPIRY_INTERVAL);
+        let mut history_tick = tokio::time::interval_at(
+            Instant::now() + config.history_interval,
+            config.history_interval,
+        );
+        loop {
+            state.beat();
+            let req = tokio::select! {
+                req = rx.recv() => req,
+                _ = history_tick.tick(), if config.history_samples > 0 => {
+                    let mut counts: HashMap<Arc<str>, u64> = pages
+                        .key_map
+                        .iter()
+                        .map(|(key, page)| (key.clone(), page.count() + peers.count(key)))
+                        .collect();
+                    for key in peers.counts.keys() {
+                        if !pages.key_map.contains_key(key.as_str()) {
+                            counts.insert(Arc::from(key.as_str()), peers.count(key));
+                        }
+                    }
+                    history.sample(unix_time(), counts);
+                    continue;
                 }
-                Some(Request::Unregister(handle)) => {
-                    debug!("Unregistering {}", handle.id);
-                    let key = handle.key;
-                    let k2 = key.clone();
-                    tx_map.remove(&handle.id);
-                    let remaining = u64::try_from(
-                        key_map
-                            .entry(key.clone())
-                            .and_modify(|e| {
-                                e.remove(&handle.id);
-                            })
-                            .or_default()
-                            .len(),
-                    )
-                    .expect("TODO");
-                    if remaining == 0 {
-                        key_map.remove(&k2);
-                    } else {
-                        match key_map.get(&k2) {
-                            Some(set) => {
-                                Self::publish(&tx_map, set, remaining).await;
+                _ = expiry.tick() => {
+                    peaks.roll(unix_time());
+                    let now = Instant::now();
+                    for key in peers.expire(now) {
+                        match pages.key_map.get(key.as_str()) {
+                            Some(page) => {
+                                if page.publish(page.count() + peers.count(&key)) {
+                                    notify_change(&changes);
+                                }
                             }
-                            None => {
-                                warn!("CAN'T HAPPEN: Double unregister??")
+                            None => pages.bump_version(),
+                        }
+                    }
+                    for (key, id, viewer) in leases.expire(now) {
+                        unregister(&mut pages, &peers, &changes, &key, id, &viewer);
+                    }
+                    continue;
+                }
+            };
+            match req {
+                Some(Request::Register(key, viewer, ch)) => {
+                    debug!("Registering");
+                    REGISTRATIONS.inc();
+                    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+                    let (key, viewer, rx) = pages.add(&key, id, viewer);
+                    let handle = Handle {
+                        id,
+                        key: key.clone(),
+                        viewer,
+                        ch: rx,
+                        control: tx.clone(),
+                        pending: None,
+                        closed: false,
+                    };
+                    publish_added(&pages, &peers, &mut peaks, &changes, &key);
+                    if ch.send(handle).is_err() {
+                        warn!("Failed to send handle back during register()");
+                    };
+                }
+                Some(Request::Reregister(id, key, viewer, ch)) => {
+                    debug!("Re-registering {id}");
+                    let (key, _, rx) = pages.add(&key, id, viewer);
+                    publish_added(&pages, &peers, &mut peaks, &changes, &key);
+                    if ch.send(rx).is_err() {
+                        debug!("Failed to send channel back during re-register");
+                    }
+                }
+                Some(Request::Unregister(id, key, viewer)) => {
+                    debug!("Unregistering {id}");
+                    unregister(&mut pages, &peers, &changes, &key, id, &viewer);
+                }
+                Some(Request::Lease(key, lease, viewer, expires, ch)) => {
+                    let rx = match leases.leases.entry((key, lease)) {
+                        Entry::Occupied(mut entry) => {
+                            let entry = entry.get_mut();
+                            entry.expires = entry.expires.max(expires);
+                            pages.key_map[&entry.key].tx.subscribe()
+                        }
+                        Entry::Vacant(entry) => {
+                            debug!("Registering lease");
+                            REGISTRATIONS.inc();
+                            let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+                            let (key, viewer, rx) = pages.add(&entry.key().0, id, viewer);
+                            publish_added(&pages, &peers, &mut peaks, &changes, &key);
+                            entry.insert(LeaseEntry {
+                                id,
+                                key,
+                                viewer,
+                                expires,
+                            });
+                            rx
+                        }
+                    };
+                    if ch.send(rx).is_err() {
+                        debug!("Failed to send channel back during lease");
+                    }
+                }
+                Some(Request::LocalCounts(ch)) => {
+                    let counts = pages
+                        .key_map
+                        .iter()
+                        .filter(|(_, page)| page.count() > 0)
+                        .map(|(key, page)| (key.to_string(), page.count()))
+                        .collect();
+                    if ch.send(counts).is_err() {
+                        warn!("Failed to send local counts back");
+                    }
+                }
+                Some(Request::PeerCounts(peer, counts, expires)) => {
+                    for key in peers.update(peer, counts, expires) {
+                        match pages.key_map.get_key_value(key.as_str()) {
+                            Some((key, page)) => {
+                                let total = page.count() + peers.count(key);
+                                if page.publish(total) {
+                                    notify_change(&changes);
+                                }
+                                peaks.observe(key, total, unix_time(), page.labeled);
                             }
+                            None => pages.bump_version(),
                         }
                     }
-                    debug!("After unregister: {} active connections", tx_map.len());
-                    // TODO: if 0, remove from map.
-                    match i64::try_from(remaining) {
-                        Ok(v) => PAGE_ACTIVE.with_label_values(&[&key]).set(v),
-                        Err(e) => error!("Failed to convert {remaining} to i64 in unregister: {e}"),
+                }
+                Some(Request::History(key, ch)) => {
+                    if ch.send(history.get(&key)).is_err() {
+                        warn!("Failed to send history back");
+                    }
+                }
+                Some(Request::Peaks(key, ch)) => {
+                    if ch.send(peaks.get(&key)).is_err() {
+                        warn!("Failed to send peaks back");
+                    }
+                }
+                Some(Request::Top(n, host, ch)) => {
+                    let mut top: Vec<TopPage> = pages
+                        .key_map
+                        .iter()
+                        .filter(|(key, _)| host.as_deref().is_none_or(|h| key_has_host(key, h)))
+                        .map(|(key, page)| TopPage {
+                            url: key.to_string(),
+                            count: page.count() + peers.count(key),
+                        })
+                        .filter(|page| page.count > 0)
+                        .collect();
+                    rank(&mut top, n);
+                    if ch.send(top).is_err() {
+                        warn!("Failed to send top pages back");
+                    }
+                }
+                Some(Request::Counts(keys, ch)) => {
+                    let counts = keys
+                        .iter()
+                        .map(|key| match pages.key_map.get(key.as_str()) {
+                            Some(page) => *page.tx.borrow(),
+                            None => Count {
+                                count: peers.count(key),
+                                version: pages.versions.load(Ordering::Relaxed),
+                            },
+                        })
+                        .collect();
+                    if ch.send(counts).is_err() {
+                        warn!("Failed to send counts back");
+                    }
+                }
+                Some(Request::Total(ch)) => {
+                    let total =
+                        pages.key_map.values().map(Page::count).sum::<u64>() + peers.total();
+                    if ch.send(total).is_err() {
+                        warn!("Failed to send total back");
                     }
-                    TOTAL_ACTIVE.set(i64::try_from(tx_map.len()).unwrap());
                 }
                 #[cfg(test)]
                 Some(Request::Stop) => break,
+                #[cfg(test)]
+                Some(Request::Panic) => panic!("registry shard panic requested by test"),
+                #[cfg(test)]
+                Some(Request::Stall(d)) => tokio::time::sleep(d).await,
                 None => {
                     warn!("control channel shutting down?");
                     break;
@@ -264,30 +2102,212 @@ impl Registry {
     }
 
     pub async fn register(&self, key: &str) -> Option<Handle> {
-        let (tx, mut rx) = mpsc::channel(CHANNEL_SIZE);
-        if let Err(err) = self.send(Request::Register(key.to_string(), tx)).await {
+        self.register_as(key, Viewer::Anonymous).await
+    }
+
+    /// Register a handle for a key, counted as `viewer`.
+    pub async fn register_as(&self, key: &str, viewer: Viewer) -> Option<Handle> {
+        let (tx, rx) = oneshot::channel();
+        let req = Request::Register(key.to_string(), viewer, tx);
+        if let Err(err) = self.shard(key).ch.send(req).await {
             warn!("Failed to register: {}", err);
             return None;
         }
-        let rfut = rx.recv().fuse();
-        let tfut = Delay::new(Duration::from_secs(60)).fuse();
-        pin_mut!(rfut, tfut);
-        select! {
-            ret = rfut => ret,
-            _ = tfut => {
-                error!("Register timeout");
-                std::process::exit(1);
+        match tokio::time::timeout(REGISTER_TIMEOUT, rx).await {
+            Ok(ret) => ret.ok(),
+            Err(_) => {
+                warn!("Register timeout");
+                TIMEOUTS.with_label_values(&["register"]).inc();
+                None
             }
         }
     }
 
-    async fn send(&self, req: Request) -> Result<(), SendError<Request>> {
-        self.ch.send(req).await
+    /// Count `viewer` as a viewer of `key` until `ttl` from now, for clients
+    /// that poll instead of keeping a connection open.
+    ///
+    /// The lease is identified by the key and `lease`, and calling this again
+    /// with the same ones renews it. Renewals never shorten a lease, and
+    /// can't change its viewer.
+    pub async fn lease(
+        &self,
+        key: &str,
+        lease: &str,
+        viewer: Viewer,
+        ttl: Duration,
+    ) -> Option<Lease> {
+        let (tx, rx) = oneshot::channel();
+        let req = Request::Lease(
+            key.to_string(),
+            lease.to_string(),
+            viewer,
+            Instant::now() + ttl,
+            tx,
+        );
+        if let Err(err) = self.shard(key).ch.send(req).await {
+            warn!("Failed to request lease: {}", err);
+            return None;
+        }
+        Some(Lease { ch: rx.await.ok()? })
+    }
+
+    /// Check if all shards are running.
+    ///
+    /// This is false while a shard that panicked or stalled is restarting.
+    pub fn is_ready(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| shard.state.ready.load(Ordering::Relaxed))
+    }
+
+    /// Get the number of local handles per key.
+    pub async fn local_counts(&self) -> Option<HashMap<String, u64>> {
+        let mut counts = HashMap::new();
+        for shard in &self.shards {
+            let (tx, rx) = oneshot::channel();
+            if let Err(err) = shard.ch.send(Request::LocalCounts(tx)).await {
+                warn!("Failed to request local counts: {}", err);
+                return None;
+            }
+            counts.extend(rx.await.ok()?);
+        }
+        Some(counts)
+    }
+
+    /// Get the sampled count history of a key, oldest first.
+    pub async fn history(&self, key: &str) -> Option<Vec<Sample>> {
+        let (tx, rx) = oneshot::channel();
+        if let Err(err) = self
+            .shard(key)
+            .ch
+            .send(Request::History(key.to_string(), tx))
+            .await
+        {
+            warn!("Failed to request history: {}", err);
+            return None;
+        }
+        rx.await.ok()
+    }
+
+    /// Get the peak counts of a key, if it has ever had any viewers.
+    pub async fn peaks(&self, key: &str) -> Option<PagePeaks> {
+        let (tx, rx) = oneshot::channel();
+        if let Err(err) = self
+            .shard(key)
+            .ch
+            .send(Request::Peaks(key.to_string(), tx))
+            .await
+        {
+            warn!("Failed to request peaks: {}", err);
+            return None;
+        }
+        rx.await.ok().flatten()
+    }
+
+    /// Get the `n` keys with the most viewers, most viewers first.
+    ///
+    /// If `host` is set, only keys that are URLs on that host are included.
+    pub async fn top(&self, n: usize, host: Option<&str>) -> Option<Vec<TopPage>> {
+        let mut top = Vec::new();
+        for shard in &self.shards {
+            let (tx, rx) = oneshot::channel();
+            let req = Request::Top(n, host.map(str::to_owned), tx);
+            if let Err(err) = shard.ch.send(req).await {
+                warn!("Failed to request top pages: {}", err);
+                return None;
+            }
+            top.extend(rx.await.ok()?);
+        }
+        rank(&mut top, n);
+        Some(top)
+    }
+
+    /// Get the current counts of `keys`, in the same order, without
+    /// registering. Counts include those of cluster peers.
+    pub async fn counts(&self, keys: &[String]) -> Option<Vec<Count>> {
+        let mut split: Vec<Vec<usize>> = vec![Vec::new(); self.shards.len()];
+        for (i, key) in keys.iter().enumerate() {
+            split[self.shard_index(key)].push(i);
+        }
+        let mut counts = vec![Count::default(); keys.len()];
+        for (shard, indexes) in self.shards.iter().zip(split) {
+            if indexes.is_empty() {
+                continue;
+            }
+            let (tx, rx) = oneshot::channel();
+            let shard_keys = indexes.iter().map(|&i| keys[i].clone()).collect();
+            if let Err(err) = shard.ch.send(Request::Counts(shard_keys, tx)).await {
+                warn!("Failed to request counts: {}", err);
+                return None;
+            }
+            for (i, count) in indexes.into_iter().zip(rx.await.ok()?) {
+                counts[i] = count;
+            }
+        }
+        Some(counts)
+    }
+
+    /// Get the number of viewers of all keys, including those of cluster
+    /// peers.
+    pub async fn total(&self) -> Option<u64> {
+        let mut total = 0;
+        for shard in &self.shards {
+            let (tx, rx) = oneshot::channel();
+            if let Err(err) = shard.ch.send(Request::Total(tx)).await {
+                warn!("Failed to request total: {}", err);
+                return None;
+            }
+            total += rx.await.ok()?;
+        }
+        Some(total)
+    }
+
+    /// Set the counts a cluster peer has for its local handles.
+    ///
+    /// The counts are added to local counts until they're replaced by another
+    /// report from the same peer, or `ttl` has passed. A count of zero removes
+    /// the key for that peer.
+    pub async fn peer_counts(
+        &self,
+        peer: SocketAddr,
+        counts: HashMap<String, u64>,
+        ttl: Duration,
+    ) -> Result<(), Closed> {
+        let expires = Instant::now() + ttl;
+        let mut split: Vec<HashMap<String, u64>> = vec![HashMap::new(); self.shards.len()];
+        for (key, count) in counts {
+            split[self.shard_index(&key)].insert(key, count);
+        }
+        for (shard, counts) in self.shards.iter().zip(split) {
+            if !counts.is_empty() {
+                shard
+                    .ch
+                    .send(Request::PeerCounts(peer, counts, expires))
+                    .await
+                    .map_err(|_| Closed)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn cluster_peers(&self) -> usize {
+        self.peer_sets.len()
+    }
+
+    #[cfg(test)]
+    async fn send(&self, key: &str, req: Request) {
+        self.shard(key).ch.send(req).await.unwrap();
     }
 
     #[cfg(test)]
     pub async fn stop(self) -> Result<(), tokio::task::JoinError> {
-        self.send(Request::Stop).await.expect("TODO");
-        self._join.await
+        for shard in &self.shards {
+            shard.ch.send(Request::Stop).await.expect("TODO");
+        }
+        for shard in self.shards {
+            shard._join.await?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,1151 @@
+//! Livecount session over any websocket stream.
+//!
+//! The session registers the client with the registry, sends it count
+//! updates, pings it when idle, and closes the session when the client goes
+//! away or stops responding. Counts are sent as bare integers, or as JSON
+//! messages with [`Protocol::V2`].
+//!
+//! [`run_top`] is a similar session that instead sends the most watched
+//! pages whenever they change, and [`run_multi`] one that lets the client
+//! watch the counts of many pages without being counted. [`sse_events`] is
+//! the counting session for Server-Sent Events clients, and [`poll`] for
+//! long-polling clients.
+//!
+//! The websocket is any stream and sink of [`Frame`], so this works with
+//! whatever websocket implementation the embedding server uses. Adapt its
+//! message type with `StreamExt::map` and `SinkExt::with`.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use log::{debug, error, info, trace, warn};
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::keepalive::{Conn, Event, Keepalive};
+use crate::registry::{Count, Handle, Registry, TopPage, Viewer};
+use crate::registry::{PING_LATENCY, TIMEOUTS, UPDATES_SENT, WS_RX_TYPE};
+
+static THE_PAST: LazyLock<std::time::Instant> = LazyLock::new(std::time::Instant::now);
+
+mod static_assert {
+    /// This asserts that ping life is at least `MIN_PING_SECS` seconds before end of
+    /// websocket life.
+    const _MUST_WORK: u64 = super::MAX_WS_LIFE_PING_SECS - super::_MIN_PING_SECS;
+}
+
+const _MIN_PING_SECS: u64 = 10;
+
+/// Max lifetime of an idle websocket.
+const MAX_WS_LIFE_SECS: u64 = 600; // 10 minutes.
+pub const MAX_WS_LIFE: Duration = Duration::from_secs(MAX_WS_LIFE_SECS);
+
+/// Websocket lifetime after which a ping is sent.
+const MAX_WS_LIFE_PING_SECS: u64 = MAX_WS_LIFE_SECS - 60;
+pub const MAX_WS_LIFE_PING: Duration = Duration::from_secs(MAX_WS_LIFE_PING_SECS);
+
+/// Timeout for sending websocket message.
+const MAX_WS_SEND_TIME: Duration = Duration::from_secs(5);
+
+/// Min time between rankings of top pages.
+const TOP_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Max number of pages one multiplexed session can subscribe to.
+pub const MAX_SUBSCRIPTIONS: usize = 100;
+
+/// Max number of client commands of a multiplexed session, or client
+/// messages of a v2 session, waiting to be handled. A client sending more is
+/// disconnected.
+const MAX_QUEUED_COMMANDS: usize = 32;
+
+/// How long a long-poll request waits for the count to change.
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How long a long-polling client keeps being counted after its last poll
+/// returned.
+pub const POLL_GRACE: Duration = Duration::from_secs(10);
+
+/// How often the lease of an outstanding poll is renewed. Well within
+/// `POLL_GRACE`, so that the lease never runs out while the poll waits.
+const POLL_RENEW_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Websocket subprotocol selecting [`Protocol::V2`].
+pub const V2_SUBPROTOCOL: &str = "livecount.v2";
+
+/// Message format of a counting session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Counts are sent as bare integers, and anything the client sends is
+    /// ignored.
+    #[default]
+    V1,
+
+    /// Typed JSON messages both ways. See [`ServerMessage`] and
+    /// [`ClientMessage`].
+    V2,
+}
+
+impl Protocol {
+    /// Pick the protocol from the client's `Sec-WebSocket-Protocol` header.
+    /// Returns the protocol and the subprotocol to answer with, if any.
+    pub fn negotiate(header: Option<&str>) -> (Protocol, Option<&'static str>) {
+        let offered = header
+            .into_iter()
+            .flat_map(|h| h.split(','))
+            .any(|p| p.trim() == V2_SUBPROTOCOL);
+        if offered {
+            (Protocol::V2, Some(V2_SUBPROTOCOL))
+        } else {
+            (Protocol::V1, None)
+        }
+    }
+}
+
+/// A message to the client of a v2 session.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Number of viewers of the page.
+    Count { count: u64 },
+
+    /// Number of viewers of all pages, in reply to [`ClientMessage::Total`].
+    Total { count: u64 },
+
+    /// Something the client may want to show or act on, e.g. that the
+    /// session is about to be closed.
+    Notice { message: String },
+
+    /// A client message could not be handled.
+    Error { message: String },
+
+    /// Reply to [`ClientMessage::Ping`].
+    Pong,
+}
+
+/// A message from the client of a v2 session.
+#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Ask for a [`ServerMessage::Pong`].
+    Ping,
+
+    /// Ask for a [`ServerMessage::Total`].
+    Total,
+}
+
+/// A websocket message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// Top pages, as sent to clients.
+#[derive(serde::Serialize)]
+pub(crate) struct TopReply<'a> {
+    pub pages: &'a [TopPage],
+}
+
+/// Create a keepalive scheduler with the timeouts used by sessions.
+///
+/// One scheduler should be shared by all sessions.
+pub fn keepalive() -> Keepalive {
+    Keepalive::new(MAX_WS_LIFE, MAX_WS_LIFE_PING)
+}
+
+/// Send a message on a websocket, with a timeout.
+///
+/// On error, return a one-word string suitable for putting in the prometheus
+/// metric.
+async fn websocket_send<S, E>(tx: &mut S, msg: Frame) -> std::result::Result<(), String>
+where
+    S: Sink<Frame, Error = E> + Unpin,
+    E: Display,
+{
+    let timeout = tokio::time::sleep(MAX_WS_SEND_TIME);
+    tokio::pin!(timeout);
+    tokio::select! {
+        _ = timeout => {
+            Err("timeout".to_string())
+        },
+        r = tx.send(msg) => {
+            match r {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    info!("Failed to send websocket message: {e}");
+                    Err(e.to_string())
+                }
+            }
+        }
+    }
+}
+
+/// Run a session counting the client of `socket` as a viewer of `key`.
+///
+/// `viewer` decides how the client is counted. See [`Viewer`]. `protocol`
+/// decides the message format, and is normally negotiated with
+/// [`Protocol::negotiate`].
+///
+/// Returns when the session is over. The caller is responsible for any
+/// validation of the key before calling this.
+pub async fn run<S, E>(
+    socket: S,
+    key: &str,
+    viewer: Viewer,
+    protocol: Protocol,
+    reg: &Registry,
+    keepalive: &Keepalive,
+) where
+    S: Stream<Item = Result<Frame, E>> + Sink<Frame, Error = E>,
+    E: Display,
+{
+    // Split the websocket so that we can give it to separate futures.
+    let (mut tx, mut rx) = socket.split();
+
+    // See https://biriukov.dev/docs/async-rust-tokio-io/3-tokio-io-patterns/ pattern.
+    let Some(mut handle) = reg.register_as(key, viewer).await else {
+        warn!("Failed to register websocket for {key}");
+        return;
+    };
+
+    // Keepalive state. Activity is recorded here, and the shared keepalive
+    // scheduler tells us when to send a ping or give up on the client.
+    let state = keepalive.add();
+
+    // v2 client messages, handed from the reader to the writer.
+    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel::<String>(MAX_QUEUED_COMMANDS);
+
+    // Async that sends count updates and pings on the websocket, and times out
+    // the connection. Count updates are read straight from the registry
+    // handle, so that a slow client only ever gets the latest count.
+    //
+    // Any async returning Err will terminate both.
+    let to_client = async {
+        loop {
+            let (kind, msg) = tokio::select! {
+                msg = handle.next() => {
+                    let Some(count) = msg else {
+                        debug!("Registry closing");
+                        return Err::<(), _>("registry closing".to_owned());
+                    };
+                    match protocol {
+                        Protocol::V1 => ("data", format!("{count}")),
+                        Protocol::V2 => ("data", encode(&ServerMessage::Count { count })?),
+                    }
+                }
+                Some(msg) = msg_rx.recv() => {
+                    let reply = match serde_json::from_str::<ClientMessage>(&msg) {
+                        Ok(ClientMessage::Ping) => ServerMessage::Pong,
+                        Ok(ClientMessage::Total) => {
+                            let Some(count) = reg.total().await else {
+                                debug!("Registry closing");
+                                return Err("registry closing".to_owned());
+                            };
+                            ServerMessage::Total { count }
+                        }
+                        Err(e) => {
+                            debug!("Bad client message {msg:?}: {e}");
+                            ServerMessage::Error { message: format!("bad message: {e}") }
+                        }
+                    };
+                    ("reply", encode(&reply)?)
+                }
+                event = state.next() => {
+                    if event == Event::Expire && protocol == Protocol::V2 {
+                        let notice = ServerMessage::Notice {
+                            message: "session expired, reconnect".to_owned(),
+                        };
+                        // The session is ending either way.
+                        let _ = websocket_send(&mut tx, Frame::Text(encode(&notice)?)).await;
+                    }
+                    keepalive_event(&mut tx, event).await?;
+                    continue;
+                }
+            };
+            match websocket_send(&mut tx, Frame::Text(msg)).await {
+                Err(e) => {
+                    warn!("Error sending on websocket: {e}");
+                    UPDATES_SENT.with_label_values(&[kind, &e]).inc();
+                    return Err("sending on websocket".to_owned());
+                }
+                Ok(_) => {
+                    UPDATES_SENT.with_label_values(&[kind, "ok"]).inc();
+                }
+            }
+        }
+    };
+
+    // Async that reads from client.
+    let from_client = from_client(&mut rx, &state, |text| match protocol {
+        Protocol::V1 => Ok(()),
+        Protocol::V2 => msg_tx
+            .try_send(text.to_owned())
+            .map_err(|_| "too many queued messages".to_owned()),
+    });
+
+    // Run both asyncs. If either of them returns error, terminate both.
+    if let Err(e) = tokio::try_join!(to_client, from_client) {
+        debug!("WS asyncs ended with: {e:?}");
+    }
+
+    debug!("WS Terminating");
+    handle.close().await;
+}
+
+/// Pages ranked by most viewers, overall and per host.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Ranking {
+    all: Vec<TopPage>,
+    by_host: HashMap<String, Vec<TopPage>>,
+}
+
+impl Ranking {
+    /// Keep the top `max` of `pages`, which are ranked already, overall and
+    /// per host.
+    fn new(pages: Vec<TopPage>, max: usize) -> Self {
+        let mut by_host: HashMap<String, Vec<TopPage>> = HashMap::new();
+        for page in &pages {
+            let Some(host) = url::Url::parse(&page.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+            else {
+                continue;
+            };
+            let top = by_host.entry(host).or_default();
+            if top.len() < max {
+                top.push(page.clone());
+            }
+        }
+        let mut all = pages;
+        all.truncate(max);
+        Ranking { all, by_host }
+    }
+
+    /// The top `n` pages, on `host` if set.
+    fn top(&self, n: usize, host: Option<&str>) -> &[TopPage] {
+        let pages = match host {
+            None => &self.all,
+            Some(host) => match self.by_host.get(host) {
+                Some(pages) => pages,
+                None => return &[],
+            },
+        };
+        &pages[..n.min(pages.len())]
+    }
+}
+
+/// Rankings of the most watched pages, shared by all [`run_top`] sessions.
+///
+/// Pages are ranked by one task, at most once every `TOP_UPDATE_INTERVAL`
+/// no matter how many sessions there are. The task runs while there are
+/// sessions. Cheap to clone.
+#[derive(Clone)]
+pub struct TopFeed {
+    inner: Arc<TopFeedInner>,
+}
+
+struct TopFeedInner {
+    reg: Arc<Registry>,
+
+    /// Max number of pages a session can ask for.
+    max: usize,
+
+    /// Rankings of the running task, if any. `None` until the first ranking.
+    rankings: Mutex<Option<watch::Receiver<Option<Arc<Ranking>>>>>,
+}
+
+impl TopFeed {
+    /// Create a feed of the top `max` pages of `reg`, overall and per host.
+    pub fn new(reg: Arc<Registry>, max: usize) -> Self {
+        TopFeed {
+            inner: Arc::new(TopFeedInner {
+                reg,
+                max,
+                rankings: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Subscribe to rankings, starting the task if it's not running. The
+    /// latest ranking, if any, is marked as changed.
+    fn subscribe(&self) -> watch::Receiver<Option<Arc<Ranking>>> {
+        let mut rankings = self.inner.rankings.lock().unwrap();
+        if let Some(rx) = rankings.as_ref() {
+            let mut rx = rx.clone();
+            rx.mark_changed();
+            return rx;
+        }
+        debug!("Starting to rank top pages");
+        let (tx, rx) = watch::channel(None);
+        *rankings = Some(rx.clone());
+        tokio::spawn(rank_top_pages(self.inner.clone(), tx));
+        rx
+    }
+}
+
+async fn rank_top_pages(feed: Arc<TopFeedInner>, tx: watch::Sender<Option<Arc<Ranking>>>) {
+    let mut changes = feed.reg.changes();
+    loop {
+        changes.mark_unchanged();
+        let Some(pages) = feed.reg.top(usize::MAX, None).await else {
+            debug!("Registry closing");
+            break;
+        };
+        let ranking = Ranking::new(pages, feed.max);
+        tx.send_if_modified(|current| {
+            if current.as_deref() == Some(&ranking) {
+                return false;
+            }
+            *current = Some(Arc::new(ranking));
+            true
+        });
+
+        tokio::time::sleep(TOP_UPDATE_INTERVAL).await;
+        if changes.changed().await.is_err() {
+            debug!("Registry closing");
+            break;
+        }
+        // Checked under the lock, so that no session subscribes in between.
+        let mut rankings = feed.rankings.lock().unwrap();
+        if tx.receiver_count() <= 1 {
+            debug!("No more top sessions, stopping ranking");
+            *rankings = None;
+            return;
+        }
+    }
+    *feed.rankings.lock().unwrap() = None;
+}
+
+/// Run a session sending the client the `n` pages with the most viewers, as
+/// JSON, whenever that changes.
+///
+/// If `host` is set, only pages on that host are included. `n` must be at
+/// most the max of the feed. Updates are sent at most once every
+/// `TOP_UPDATE_INTERVAL`.
+pub async fn run_top<S, E>(
+    socket: S,
+    feed: &TopFeed,
+    n: usize,
+    host: Option<&str>,
+    keepalive: &Keepalive,
+) where
+    S: Stream<Item = Result<Frame, E>> + Sink<Frame, Error = E>,
+    E: Display,
+{
+    let (mut tx, mut rx) = socket.split();
+    let mut rankings = feed.subscribe();
+    let state = keepalive.add();
+
+    let to_client = async {
+        let mut last: Option<Vec<TopPage>> = None;
+        loop {
+            tokio::select! {
+                r = rankings.changed() => {
+                    if r.is_err() {
+                        debug!("Registry closing");
+                        return Err::<(), _>("registry closing".to_owned());
+                    }
+                }
+                event = state.next() => {
+                    keepalive_event(&mut tx, event).await?;
+                    continue;
+                }
+            }
+            let Some(ranking) = rankings.borrow_and_update().clone() else {
+                continue;
+            };
+            let top = ranking.top(n, host);
+            if last.as_deref() != Some(top) {
+                let msg = match serde_json::to_string(&TopReply { pages: top }) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        error!("Failed to encode top pages: {e}");
+                        return Err("encoding top pages".to_owned());
+                    }
+                };
+                match websocket_send(&mut tx, Frame::Text(msg)).await {
+                    Err(e) => {
+                        warn!("Error sending on websocket: {e}");
+                        UPDATES_SENT.with_label_values(&["top", &e]).inc();
+                        return Err("sending on websocket".to_owned());
+                    }
+                    Ok(_) => {
+                        UPDATES_SENT.with_label_values(&["top", "ok"]).inc();
+                    }
+                }
+                last = Some(top.to_vec());
+            }
+        }
+    };
+    let from_client = from_client(&mut rx, &state, |_| Ok(()));
+
+    if let Err(e) = tokio::try_join!(to_client, from_client) {
+        debug!("Top WS asyncs ended with: {e:?}");
+    }
+    debug!("Top WS Terminating");
+}
+
+/// A command from the client of a multiplexed session.
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    Subscribe(&'a str),
+    Unsubscribe(&'a str),
+}
+
+impl<'a> Command<'a> {
+    fn parse(text: &'a str) -> Option<Self> {
+        match text.trim_end().split_once(' ')? {
+            ("sub", url) => Some(Command::Subscribe(url)),
+            ("unsub", url) => Some(Command::Unsubscribe(url)),
+            _ => None,
+        }
+    }
+}
+
+/// Run a session where the client watches the counts of any number of pages,
+/// up to `MAX_SUBSCRIPTIONS`, without being counted on them.
+///
+/// The client sends `sub <url>` and `unsub <url>` text messages. Count
+/// updates are sent as `<count> <url>`, with the URL as the client sent it.
+/// Rejected subscriptions are answered with `error <url>`.
+///
+/// `key_for` maps a subscribed URL to its registry key, or rejects it with a
+/// reason to log.
+pub async fn run_multi<S, E, F>(socket: S, key_for: F, reg: &Registry, keepalive: &Keepalive)
+where
+    S: Stream<Item = Result<Frame, E>> + Sink<Frame, Error = E>,
+    E: Display,
+    F: Fn(&str) -> Result<String, String>,
+{
+    let (mut tx, mut rx) = socket.split();
+    let state = keepalive.add();
+
+    // Commands are handed from the reader to the writer, which owns the
+    // subscriptions.
+    let (cmd_tx, mut cmd_rx) = tokio::sync::mpsc::channel::<String>(MAX_QUEUED_COMMANDS);
+
+    // Subscriptions, by URL as the client sent it.
+    let mut subs: HashMap<String, Handle> = HashMap::new();
+
+    let to_client = async {
+        loop {
+            let msg = tokio::select! {
+                (url, count) = next_update(&mut subs), if !subs.is_empty() => {
+                    let Some(count) = count else {
+                        debug!("Registry closing");
+                        return Err::<(), _>("registry closing".to_owned());
+                    };
+                    format!("{count} {url}")
+                }
+                cmd = cmd_rx.recv() => {
+                    let Some(cmd) = cmd else {
+                        return Err("client gone".to_owned());
+                    };
+                    match subscription_command(&cmd, &mut subs, &key_for, reg).await {
+                        Ok(()) => continue,
+                        Err(url) => format!("error {url}"),
+                    }
+                }
+                event = state.next() => {
+                    keepalive_event(&mut tx, event).await?;
+                    continue;
+                }
+            };
+            match websocket_send(&mut tx, Frame::Text(msg)).await {
+                Err(e) => {
+                    warn!("Error sending on websocket: {e}");
+                    UPDATES_SENT.with_label_values(&["multi", &e]).inc();
+                    return Err("sending on websocket".to_owned());
+                }
+                Ok(_) => {
+                    UPDATES_SENT.with_label_values(&["multi", "ok"]).inc();
+                }
+            }
+        }
+    };
+    let from_client = from_client(&mut rx, &state, |text| {
+        cmd_tx
+            .try_send(text.to_owned())
+            .map_err(|_| "too many queued commands".to_owned())
+    });
+
+    if let Err(e) = tokio::try_join!(to_client, from_client) {
+        debug!("Multi WS asyncs ended with: {e:?}");
+    }
+    debug!("Multi WS Terminating");
+    for (_, handle) in subs {
+        handle.close().await;
+    }
+}
+
+/// Wait for the next count update of any subscription. Returns the URL of
+/// the subscription, and the count or `None` if the registry is closing.
+async fn next_update(subs: &mut HashMap<String, Handle>) -> (&str, Option<u64>) {
+    let updates = subs
+        .iter_mut()
+        .map(|(url, handle)| Box::pin(async move { (url.as_str(), handle.next().await) }));
+    futures_util::future::select_all(updates).await.0
+}
+
+/// Act on a command from the client of a multiplexed session.
+///
+/// Returns the URL of a rejected subscription as the error.
+async fn subscription_command<F>(
+    cmd: &str,
+    subs: &mut HashMap<String, Handle>,
+    key_for: &F,
+    reg: &Registry,
+) -> Result<(), String>
+where
+    F: Fn(&str) -> Result<String, String>,
+{
+    match Command::parse(cmd) {
+        None => {
+            debug!("Ignoring unknown command {cmd:?}");
+            Ok(())
+        }
+        Some(Command::Subscribe(url)) => {
+            if subs.contains_key(url) {
+                return Ok(());
+            }
+            if subs.len() >= MAX_SUBSCRIPTIONS {
+                debug!("Rejecting subscription to {url}: too many subscriptions");
+                return Err(url.to_owned());
+            }
+            let key = key_for(url).map_err(|e| {
+                debug!("Rejecting subscription to {url}: {e}");
+                url.to_owned()
+            })?;
+            let Some(handle) = reg.register_as(&key, Viewer::Observer).await else {
+                warn!("Failed to register subscription for {key}");
+                return Err(url.to_owned());
+            };
+            subs.insert(url.to_owned(), handle);
+            Ok(())
+        }
+        Some(Command::Unsubscribe(url)) => {
+            if let Some(handle) = subs.remove(url) {
+                handle.close().await;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// An event for a Server-Sent Events client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SseEvent {
+    /// Number of viewers of the page.
+    Count(u64),
+
+    /// The stream has been idle for a while.
+    Ping,
+}
+
+/// Stream the counts of `handle` to a Server-Sent Events client.
+///
+/// SSE clients can't answer pings, so the stream asks for a ping event when
+/// idle, like a websocket session would send a ping, and ends at the max
+/// websocket lifetime. Browsers reconnect by themselves when it ends. The
+/// handle is unregistered when the stream ends or is dropped.
+pub fn sse_events(handle: Handle, keepalive: &Keepalive) -> impl Stream<Item = SseEvent> {
+    let state = keepalive.add();
+    futures_util::stream::unfold((handle, state), |(mut handle, state)| async move {
+        let event = tokio::select! {
+            count = handle.next() => {
+                let Some(count) = count else {
+                    debug!("Registry closing");
+                    return None;
+                };
+                // Only queued: the server sends events after they're yielded,
+                // and doesn't report failures back.
+                UPDATES_SENT.with_label_values(&["sse", "queued"]).inc();
+                SseEvent::Count(count)
+            }
+            event = state.next() => {
+                if event == Event::Expire {
+                    debug!("Max SSE time exceeded");
+                    TIMEOUTS.with_label_values(&["sse_final"]).inc();
+                    handle.close().await;
+                    return None;
+                }
+                TIMEOUTS.with_label_values(&["sse_ping"]).inc();
+                UPDATES_SENT.with_label_values(&["sse_ping", "queued"]).inc();
+                SseEvent::Ping
+            }
+        };
+        Some((event, (handle, state)))
+    })
+}
+
+/// Long-poll the count of `key`, counting the client as `viewer` with the
+/// lease `lease_id` while the poll is outstanding and for `POLL_GRACE` after.
+///
+/// Returns as soon as the count differs from `seen`, the last count the
+/// client got, or when `POLL_TIMEOUT` has passed. `None` is returned if the
+/// registry is gone.
+pub async fn poll(
+    reg: &Registry,
+    key: &str,
+    lease_id: &str,
+    viewer: Viewer,
+    seen: Option<Count>,
+) -> Option<Count> {
+    // Leases can't be shortened, so lease for the grace period only, and
+    // renew while waiting.
+    let mut lease = reg.lease(key, lease_id, viewer.clone(), POLL_GRACE).await?;
+    let current = lease.current();
+    if seen != Some(current) {
+        UPDATES_SENT.with_label_values(&["poll", "ok"]).inc();
+        return Some(current);
+    }
+    let deadline = tokio::time::Instant::now() + POLL_TIMEOUT;
+    let count = loop {
+        let renew = tokio::time::Instant::now() + POLL_RENEW_INTERVAL;
+        match tokio::time::timeout_at(renew.min(deadline), lease.changed()).await {
+            Ok(Some(count)) => {
+                UPDATES_SENT.with_label_values(&["poll", "ok"]).inc();
+                break count;
+            }
+            Ok(None) => {
+                // The shard was restarted. The next poll leases again.
+                debug!("Lease for {key} closed");
+                return Some(current);
+            }
+            Err(_) if renew < deadline => {
+                reg.lease(key, lease_id, viewer.clone(), POLL_GRACE).await?;
+            }
+            Err(_) => {
+                TIMEOUTS.with_label_values(&["poll"]).inc();
+                break current;
+            }
+        }
+    };
+    // Count the client for the grace period from the reply, not from the
+    // last renewal.
+    reg.lease(key, lease_id, viewer, POLL_GRACE).await?;
+    Some(count)
+}
+
+/// Encode a message to a v2 client.
+fn encode(msg: &ServerMessage) -> Result<String, String> {
+    serde_json::to_string(msg).map_err(|e| {
+        error!("Failed to encode {msg:?}: {e}");
+        "encoding message".to_owned()
+    })
+}
+
+/// Act on a keepalive event, by sending a ping or ending the session.
+async fn keepalive_event<S, E>(tx: &mut S, event: Event) -> Result<(), String>
+where
+    S: Sink<Frame, Error = E> + Unpin,
+    E: Display,
+{
+    if event == Event::Expire {
+        debug!("Max websocket time exceeded");
+        TIMEOUTS.with_label_values(&["final"]).inc();
+        return Err("timeout".to_owned());
+    }
+
+    // Send a ping.
+    debug!("Max websocket ping time exceeded. Sending ping.");
+    TIMEOUTS.with_label_values(&["ping"]).inc();
+    let nanos = (std::time::Instant::now() - *THE_PAST).as_nanos();
+    let msg = Frame::Ping(format!("livecount {nanos}").into_bytes());
+    match websocket_send(tx, msg).await {
+        Err(e) => {
+            warn!("Error sending ping on websocket: {e}");
+            UPDATES_SENT.with_label_values(&["ping", &e]).inc();
+            Err("error sending on websocket".to_owned())
+        }
+        Ok(_) => {
+            UPDATES_SENT.with_label_values(&["ping", "ok"]).inc();
+            Ok(())
+        }
+    }
+}
+
+/// Read from the client, recording its activity, until it goes away.
+/// Text messages are passed to `on_text`, and if that fails the client is
+/// disconnected.
+///
+/// Always returns an error, saying why the client is gone.
+async fn from_client<S, E, F>(rx: &mut S, state: &Conn, mut on_text: F) -> Result<(), String>
+where
+    S: Stream<Item = Result<Frame, E>> + Unpin,
+    E: Display,
+    F: FnMut(&str) -> Result<(), String>,
+{
+    loop {
+        let wsmsg = rx.next().await;
+        let now = (std::time::Instant::now() - *THE_PAST).as_nanos();
+        match wsmsg {
+            None => {
+                debug!("Got None message, disconnecting");
+                WS_RX_TYPE.with_label_values(&["none"]).inc();
+                return Err("got None message".to_owned());
+            }
+            Some(Ok(ref m)) => {
+                debug!("Got a message: {m:?}");
+                state.renew_ping();
+                match m {
+                    Frame::Close => {
+                        debug!("WS Disconnection: {m:?}");
+                        WS_RX_TYPE.with_label_values(&["away"]).inc();
+                        return Err("WS disconnection".to_owned());
+                    }
+                    Frame::Ping(_) => {
+                        WS_RX_TYPE.with_label_values(&["ping"]).inc();
+                        state.renew();
+                    }
+                    Frame::Text(text) => {
+                        WS_RX_TYPE.with_label_values(&["text"]).inc();
+                        state.renew();
+                        on_text(text)?;
+                    }
+                    Frame::Binary(_) => {
+                        WS_RX_TYPE.with_label_values(&["binary"]).inc();
+                        state.renew();
+                    }
+                    Frame::Pong(data) => {
+                        WS_RX_TYPE.with_label_values(&["pong"]).inc();
+                        observe_pong(data, now);
+                        state.renew();
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                WS_RX_TYPE.with_label_values(&[format!("{e}")]).inc();
+                error!("Error receiving message? {e}");
+                return Err("error receiving message".to_owned());
+            }
+        };
+    }
+}
+
+/// Record the ping latency from a pong to one of our pings.
+fn observe_pong(data: &[u8], now: u128) {
+    let txt = String::from_utf8_lossy(data);
+    let stxt: Vec<_> = txt.split(' ').collect();
+    if stxt.len() != 2 || stxt[0] != "livecount" {
+        error!("Got pong with bad data: {txt}");
+        return;
+    }
+    match stxt[1].parse::<u128>() {
+        Ok(nanos) if now >= nanos => {
+            let rtt = std::time::Duration::from_nanos((now - nanos).try_into().unwrap_or(0));
+            let rtt_ms = rtt.as_nanos() as f64 / 1_000_000f64;
+            PING_LATENCY.observe(rtt_ms);
+            trace!("Ping RTT {rtt:?}");
+        }
+        Ok(nanos) => {
+            error!("Ping time underflow: {now} < {nanos}");
+        }
+        Err(_e) => error!("Got pong with bad data: {txt}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::channel::mpsc;
+    use futures_util::{Sink, SinkExt, Stream, StreamExt};
+
+    use super::{
+        keepalive, poll, run, run_multi, run_top, sse_events, Command, Frame, Protocol, Ranking,
+        SseEvent, TopFeed, POLL_GRACE, POLL_TIMEOUT,
+    };
+    use crate::registry::{Count, Registry, TopPage, Viewer};
+
+    /// In-memory websocket, with the client end being the other halves of
+    /// the channels.
+    struct TestSocket {
+        rx: mpsc::Receiver<Frame>,
+        tx: mpsc::Sender<Frame>,
+    }
+
+    impl Stream for TestSocket {
+        type Item = Result<Frame, mpsc::SendError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx).map(|f| f.map(Ok))
+        }
+    }
+
+    impl Sink<Frame> for TestSocket {
+        type Error = mpsc::SendError;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.tx.poll_ready_unpin(cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Frame) -> Result<(), Self::Error> {
+            self.tx.start_send_unpin(item)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.tx.poll_flush_unpin(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.tx.poll_close_unpin(cx)
+        }
+    }
+
+    fn socket() -> (TestSocket, mpsc::Sender<Frame>, mpsc::Receiver<Frame>) {
+        let (client_tx, rx) = mpsc::channel(10);
+        let (tx, client_rx) = mpsc::channel(10);
+        (TestSocket { rx, tx }, client_tx, client_rx)
+    }
+
+    #[tokio::test]
+    async fn sends_counts_until_client_closes() {
+        let reg = Registry::new();
+        let ka = keepalive();
+        let (sock1, mut c1_tx, mut c1_rx) = socket();
+        let (sock2, mut c2_tx, mut c2_rx) = socket();
+        let s1 = run(sock1, "foo", Viewer::Anonymous, Protocol::V1, &reg, &ka);
+        let s2 = async {
+            assert_eq!(c1_rx.next().await, Some(Frame::Text("1".into())));
+            let s2 = run(sock2, "foo", Viewer::Anonymous, Protocol::V1, &reg, &ka);
+            let client2 = async {
+                assert_eq!(c2_rx.next().await, Some(Frame::Text("2".into())));
+                assert_eq!(c1_rx.next().await, Some(Frame::Text("2".into())));
+                c2_tx.send(Frame::Close).await.unwrap();
+            };
+            tokio::join!(s2, client2);
+            assert_eq!(c1_rx.next().await, Some(Frame::Text("1".into())));
+            c1_tx.send(Frame::Close).await.unwrap();
+        };
+        tokio::join!(s1, s2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_sse_counts_until_expiry() {
+        let reg = Registry::new();
+        let ka = keepalive();
+        let handle = reg.register("foo").await.unwrap();
+        let events = sse_events(handle, &ka);
+        tokio::pin!(events);
+        assert_eq!(events.next().await, Some(SseEvent::Count(1)));
+        let _h2 = reg.register("foo").await.unwrap();
+        assert_eq!(events.next().await, Some(SseEvent::Count(2)));
+        assert_eq!(events.next().await, Some(SseEvent::Ping));
+        assert_eq!(events.next().await, None);
+        assert_eq!(reg.local_counts().await.unwrap().get("foo"), Some(&1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_until_count_changes() {
+        let reg = Registry::new();
+        let first = poll(&reg, "foo", "a", Viewer::Anonymous, None).await;
+        let first = first.unwrap();
+        assert_eq!(first.count, 1);
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(
+            poll(&reg, "foo", "a", Viewer::Anonymous, Some(first)).await,
+            Some(first)
+        );
+        assert!(start.elapsed() >= POLL_TIMEOUT);
+
+        let waiting = poll(&reg, "foo", "a", Viewer::Anonymous, Some(first));
+        let joining = async {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            reg.register("foo").await.unwrap()
+        };
+        let (count, _h) = tokio::join!(waiting, joining);
+        assert_eq!(
+            count,
+            Some(Count {
+                count: 2,
+                version: 2
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_polls_for_grace_period_after_reply() {
+        let reg = Registry::new();
+        let mut obs = reg.register_as("foo", Viewer::Observer).await.unwrap();
+        assert_eq!(obs.next().await, Some(0));
+
+        // Returns right away, as the client hasn't seen any count yet.
+        let start = tokio::time::Instant::now();
+        let count = poll(&reg, "foo", "a", Viewer::Anonymous, None).await;
+        assert_eq!(count.unwrap().count, 1);
+        assert_eq!(obs.next().await, Some(1));
+        assert_eq!(obs.next().await, Some(0));
+        assert!(start.elapsed() >= POLL_GRACE);
+        assert!(start.elapsed() < POLL_GRACE + std::time::Duration::from_secs(2));
+    }
+
+    #[test]
+    fn negotiates_protocol() {
+        assert_eq!(Protocol::negotiate(None), (Protocol::V1, None));
+        assert_eq!(Protocol::negotiate(Some("chat")), (Protocol::V1, None));
+        assert_eq!(
+            Protocol::negotiate(Some("chat, livecount.v2")),
+            (Protocol::V2, Some("livecount.v2"))
+        );
+    }
+
+    #[tokio::test]
+    async fn speaks_json_with_v2_clients() {
+        let reg = Registry::new();
+        let ka = keepalive();
+        let _other = reg.register("bar").await.unwrap();
+        let (sock, mut c_tx, mut c_rx) = socket();
+        let session = run(sock, "foo", Viewer::Anonymous, Protocol::V2, &reg, &ka);
+        let client = async {
+            let text = |t: &str| Some(Frame::Text(t.into()));
+            assert_eq!(c_rx.next().await, text(r#"{"type":"count","count":1}"#));
+            c_tx.send(Frame::Text(r#"{"type":"total"}"#.into()))
+                .await
+                .unwrap();
+            assert_eq!(c_rx.next().await, text(r#"{"type":"total","count":2}"#));
+            c_tx.send(Frame::Text(r#"{"type":"ping"}"#.into()))
+                .await
+                .unwrap();
+            assert_eq!(c_rx.next().await, text(r#"{"type":"pong"}"#));
+            c_tx.send(Frame::Text("1".into())).await.unwrap();
+            let Some(Frame::Text(err)) = c_rx.next().await else {
+                panic!("no error reply");
+            };
+            assert!(err.starts_with(r#"{"type":"error","message":"#), "{err}");
+            c_tx.send(Frame::Close).await.unwrap();
+        };
+        tokio::join!(session, client);
+    }
+
+    #[tokio::test]
+    async fn sends_top_pages_when_they_change() {
+        let reg = std::sync::Arc::new(Registry::new());
+        let feed = TopFeed::new(reg.clone(), 10);
+        let ka = keepalive();
+        let (sock, mut c_tx, mut c_rx) = socket();
+        let session = run_top(sock, &feed, 1, None, &ka);
+        let client = async {
+            assert_eq!(
+                c_rx.next().await,
+                Some(Frame::Text(r#"{"pages":[]}"#.into()))
+            );
+            let _h1 = reg.register("https://example.test/a").await.unwrap();
+            assert_eq!(
+                c_rx.next().await,
+                Some(Frame::Text(
+                    r#"{"pages":[{"url":"https://example.test/a","count":1}]}"#.into()
+                ))
+            );
+
+            // A later session gets the latest ranking, from the same task.
+            let (sock2, mut c2_tx, mut c2_rx) = socket();
+            let session2 = run_top(sock2, &feed, 5, Some("other.test"), &ka);
+            let client2 = async {
+                assert_eq!(
+                    c2_rx.next().await,
+                    Some(Frame::Text(r#"{"pages":[]}"#.into()))
+                );
+                c2_tx.send(Frame::Close).await.unwrap();
+            };
+            tokio::join!(session2, client2);
+            c_tx.send(Frame::Close).await.unwrap();
+        };
+        tokio::join!(session, client);
+    }
+
+    #[test]
+    fn ranks_pages_per_host() {
+        let page = |url: &str, count| TopPage {
+            url: url.to_owned(),
+            count,
+        };
+        let ranking = Ranking::new(
+            vec![
+                page("https://a.test/1", 5),
+                page("https://b.test/1", 4),
+                page("https://a.test/2", 3),
+                page("https://a.test/3", 2),
+                page("not a url", 1),
+            ],
+            2,
+        );
+        assert_eq!(
+            ranking.top(10, None),
+            [page("https://a.test/1", 5), page("https://b.test/1", 4)]
+        );
+        assert_eq!(
+            ranking.top(10, Some("a.test")),
+            [page("https://a.test/1", 5), page("https://a.test/2", 3)]
+        );
+        assert_eq!(
+            ranking.top(1, Some("b.test")),
+            [page("https://b.test/1", 4)]
+        );
+        assert!(ranking.top(10, Some("c.test")).is_empty());
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            Command::parse("sub https://a.test/x y"),
+            Some(Command::Subscribe("https://a.test/x y"))
+        );
+        assert_eq!(
+            Command::parse("unsub https://a.test/\n"),
+            Some(Command::Unsubscribe("https://a.test/"))
+        );
+        assert_eq!(Command::parse("sub"), None);
+        assert_eq!(Command::parse("foo bar"), None);
+    }
+
+    #[tokio::test]
+    async fn multiplexes_subscriptions() {
+        let reg = Registry::new();
+        let ka = keepalive();
+        let (sock, mut c_tx, mut c_rx) = socket();
+        let key_for = |url: &str| match url {
+            "bad" => Err("bad url".to_owned()),
+            url => Ok(format!("key:{url}")),
+        };
+        let session = run_multi(sock, key_for, &reg, &ka);
+        let client = async {
+            let text = |t: &str| Some(Frame::Text(t.into()));
+            c_tx.send(Frame::Text("sub a".into())).await.unwrap();
+            assert_eq!(c_rx.next().await, text("0 a"));
+            let h = reg.register("key:a").await.unwrap();
+            assert_eq!(c_rx.next().await, text("1 a"));
+
+            c_tx.send(Frame::Text("sub bad".into())).await.unwrap();
+            assert_eq!(c_rx.next().await, text("error bad"));
+            // Commands are handled in order, so "a" is unsubscribed by the
+            // time "b" is.
+            c_tx.send(Frame::Text("unsub a".into())).await.unwrap();
+            c_tx.send(Frame::Text("sub b".into())).await.unwrap();
+            assert_eq!(c_rx.next().await, text("0 b"));
+
+            h.close().await;
+            let _h = reg.register("key:b").await.unwrap();
+            assert_eq!(c_rx.next().await, text("1 b"));
+            c_tx.send(Frame::Close).await.unwrap();
+        };
+        tokio::join!(session, client);
+        let counts = reg.local_counts().await.unwrap();
+        assert!(!counts.iter().any(|(key, _)| key == "key:a"));
+    }
+}
@@ -0,0 +1,119 @@
+//! Signed page tokens, proving that a page was rendered by its site.
+//!
+//! A token is `<expiry>.<mac>`, where the expiry is in seconds since the
+//! Unix epoch, and the MAC is the hex HMAC-SHA256 of `<expiry>\n<url>` with
+//! a key shared with the site. The URL is the page key, i.e. the canonical
+//! URL the page is counted under.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a token was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Malformed, or not signed for this page with this key.
+    Invalid,
+
+    /// Valid, but expired.
+    Expired,
+}
+
+/// Key for signing and verifying tokens.
+#[derive(Clone)]
+pub struct Key(Vec<u8>);
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Key(key.into())
+    }
+
+    fn mac(&self, url: &str, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC takes keys of any length");
+        mac.update(format!("{expires}\n{url}").as_bytes());
+        mac
+    }
+
+    /// Make a token for the page key `url`, valid until `expires`.
+    pub fn sign(&self, url: &str, expires: u64) -> String {
+        let mac = self.mac(url, expires).finalize().into_bytes();
+        let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{expires}.{hex}")
+    }
+
+    /// Verify a token for the page key `url`, at `now` seconds since the
+    /// Unix epoch.
+    pub fn verify(&self, url: &str, token: &str, now: u64) -> Result<(), Error> {
+        let (expires, hex) = token.split_once('.').ok_or(Error::Invalid)?;
+        let expires: u64 = expires.parse().map_err(|_| Error::Invalid)?;
+        let mac = decode_hex(hex).ok_or(Error::Invalid)?;
+        // The MAC is checked first, so that made up tokens are invalid
+        // rather than expired.
+        self.mac(url, expires)
+            .verify_slice(&mac)
+            .map_err(|_| Error::Invalid)?;
+        if expires < now {
+            return Err(Error::Expired);
+        }
+        Ok(())
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Key};
+
+    #[test]
+    fn verifies_tokens() {
+        let key = Key::new("s3cret");
+        let url = "https://example.test/page";
+        let token = key.sign(url, 1000);
+        assert!(token.starts_with("1000."), "{token}");
+        assert_eq!(token.len(), 5 + 64);
+
+        assert_eq!(key.verify(url, &token, 999), Ok(()));
+        assert_eq!(key.verify(url, &token, 1000), Ok(()));
+        assert_eq!(key.verify(url, &token, 1001), Err(Error::Expired));
+
+        let forged = token.replace("1000.", "2000.");
+        for (url, token) in [
+            ("https://example.test/other", token.as_str()),
+            (url, forged.as_str()),
+            (url, ""),
+            (url, "1000"),
+            (url, "1000.zz"),
+            (url, "x.00"),
+            (url, &token[..token.len() - 2]),
+        ] {
+            assert_eq!(key.verify(url, token, 0), Err(Error::Invalid), "{token}");
+        }
+        assert_eq!(
+            Key::new("other").verify(url, &token, 0),
+            Err(Error::Invalid)
+        );
+    }
+}
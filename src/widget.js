@@ -0,0 +1,134 @@
+// Livecount widget, version 1.
+//
+// Include it where the count should show:
+//
+//   <script src="https://example.com/livecount/widget.js" async></script>
+//
+// Options are data- attributes on the script tag:
+//
+//   data-target: CSS selector of elements to show the count in. By default
+//     a <span class="livecount"> is put where the script tag is.
+//   data-url: page to count. Defaults to the current page.
+//   data-text: text to show, with {count} replaced by the count. Defaults to
+//     just the count.
+//   data-close-when-hidden: if set, disconnect while the page is hidden, so
+//     that background tabs are not counted.
+//   data-token: page token, if the server requires them.
+(function () {
+  "use strict";
+
+  var VERSION = 1;
+  var MIN_DELAY_MS = 1000;
+  var MAX_DELAY_MS = 60000;
+
+  var script = document.currentScript;
+  if (!script) {
+    return;
+  }
+  var data = script.dataset;
+  var server = new URL(script.src, location.href);
+  var page = data.url || location.href.split("#")[0];
+  var text = data.text || "{count}";
+  var closeWhenHidden = data.closeWhenHidden !== undefined;
+  var token = data.token;
+
+  var targets;
+  if (data.target) {
+    targets = Array.prototype.slice.call(document.querySelectorAll(data.target));
+  } else {
+    var span = document.createElement("span");
+    span.className = "livecount";
+    script.parentNode.insertBefore(span, script);
+    targets = [span];
+  }
+
+  var socket = null;
+  var timer = null;
+  var failures = 0;
+
+  function show(count) {
+    var s = text.split("{count}").join(String(count));
+    targets.forEach(function (t) {
+      t.textContent = s;
+    });
+  }
+
+  function hidden() {
+    return document.visibilityState === "hidden";
+  }
+
+  // Exponential backoff with full jitter, so that clients of a restarted
+  // server don't all come back at once.
+  function delay() {
+    var max = Math.min(MAX_DELAY_MS, MIN_DELAY_MS * Math.pow(2, failures));
+    return Math.random() * max;
+  }
+
+  function connect() {
+    timer = null;
+    if (socket || (closeWhenHidden && hidden())) {
+      return;
+    }
+    var scheme = server.protocol === "http:" ? "ws:" : "wss:";
+    var url = scheme + "//" + server.host + "/livecount/ws?l=" + encodeURIComponent(page);
+    if (token) {
+      url += "&t=" + encodeURIComponent(token);
+    }
+    var ws = new WebSocket(url, ["livecount.v2"]);
+    socket = ws;
+    ws.addEventListener("message", function (event) {
+      var msg;
+      try {
+        msg = JSON.parse(event.data);
+      } catch (e) {
+        return;
+      }
+      if (msg.type === "count") {
+        failures = 0;
+        show(msg.count);
+      }
+    });
+    ws.addEventListener("close", function () {
+      if (socket !== ws) {
+        return;
+      }
+      socket = null;
+      failures++;
+      schedule();
+    });
+  }
+
+  function schedule() {
+    if (timer !== null) {
+      return;
+    }
+    // Reconnecting is paused while the page is hidden, and resumed when it
+    // becomes visible.
+    if (hidden()) {
+      return;
+    }
+    timer = setTimeout(connect, delay());
+  }
+
+  document.addEventListener("visibilitychange", function () {
+    if (!hidden()) {
+      if (!socket) {
+        failures = 0;
+        schedule();
+      }
+      return;
+    }
+    if (timer !== null) {
+      clearTimeout(timer);
+      timer = null;
+    }
+    if (closeWhenHidden && socket) {
+      var ws = socket;
+      socket = null;
+      ws.close();
+    }
+  });
+
+  window.livecount = window.livecount || { version: VERSION };
+  connect();
+})();